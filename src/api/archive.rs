@@ -0,0 +1,378 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+//! Image tarballs (format: docker-archive, as written by `docker save`)
+
+use super::blob::Body;
+use super::Source;
+use crate::formats::docker::archive::{Config, Item, Repositories};
+use crate::formats::docker::v2::{Config as Blob, Layer, Manifest as V2};
+use crate::formats::{Digest, Manifest};
+use crate::iotools::{memfd, Validator};
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+
+/// A section of a file, which can be read by several threads at once
+struct Section {
+    file: Arc<File>,
+    offset: u64,
+    end: u64,
+}
+
+impl Read for Section {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let max = buf.len().min((self.end - self.offset) as usize);
+        if max == 0 {
+            return Ok(0);
+        }
+
+        let size = self.file.read_at(&mut buf[..max], self.offset)?;
+        self.offset += size as u64;
+        Ok(size)
+    }
+}
+
+/// An image in the archive
+#[derive(Debug)]
+struct Image {
+    names: Vec<String>,
+    manifest: V2,
+}
+
+/// A tarball written by `docker save` or `podman save`
+///
+/// Layers are read in place. Tarballs that cannot seek (i.e. pipes) are
+/// copied into memory first, since `docker save` writes `manifest.json` last.
+#[derive(Clone, Debug)]
+pub struct Archive {
+    path: String,
+    file: Arc<File>,
+
+    /// The location of each file (format: path => (offset, size))
+    entries: Arc<HashMap<String, (u64, u64)>>,
+
+    images: Arc<Vec<Image>>,
+
+    /// The path of each layer (format: digest => path)
+    blobs: Arc<HashMap<String, String>>,
+}
+
+impl Display for Archive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "docker-archive:{}", self.path)
+    }
+}
+
+impl Archive {
+    /// The media type of the (uncompressed) layers
+    const LAYER: &'static str = "application/vnd.docker.image.rootfs.diff.tar";
+
+    /// The media type of the image configuration
+    const CONFIG: &'static str = "application/vnd.docker.container.image.v1+json";
+
+    /// The digest in the name of a blob or configuration, if any
+    ///
+    /// Newer tarballs store blobs by digest (format: `blobs/ALGORITHM/HEX`),
+    /// while older ones name the configuration after its digest (format:
+    /// `HEX.json`). Legacy layer ids are not digests.
+    fn digest(path: &str) -> Option<Digest> {
+        match path.strip_prefix("blobs/") {
+            Some(blob) => blob.replacen('/', ":", 1).parse().ok(),
+            None => path.strip_suffix(".json")?.parse().ok(),
+        }
+    }
+
+    pub fn open(path: &str) -> Result<Self> {
+        let mut file = File::open(path).with_context(|| format!("unable to open {}", path))?;
+
+        let file = match file.stream_position() {
+            Err(e) if e.raw_os_error() == Some(libc::ESPIPE) => {
+                let mut spool = memfd("docker-archive")?;
+                std::io::copy(&mut file, &mut spool)
+                    .with_context(|| format!("unable to read {}", path))?;
+                spool.rewind()?;
+                spool
+            }
+
+            result => {
+                result?;
+                file
+            }
+        };
+
+        let mut entries = HashMap::new();
+        let mut tar = tar::Archive::new(&file);
+        for entry in tar.entries_with_seek()? {
+            let entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            let name = name.trim_start_matches("./").to_string();
+            entries.insert(name, (entry.raw_file_position(), entry.size()));
+        }
+
+        let mut archive = Self {
+            path: path.into(),
+            file: Arc::new(file),
+            entries: Arc::new(entries),
+            images: Default::default(),
+            blobs: Default::default(),
+        };
+
+        let items: Vec<Item> = serde_json::from_slice(&archive.read("manifest.json")?)
+            .with_context(|| format!("invalid manifest.json in {}", archive))?;
+
+        let repositories: Repositories = match archive.read("repositories") {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("invalid repositories in {}", archive))?,
+            Err(..) => Default::default(),
+        };
+
+        let mut images = Vec::new();
+        let mut blobs = HashMap::new();
+        for item in items {
+            let mut names = item.repo_tags.clone().unwrap_or_default();
+
+            // The legacy file names images by their top layer id.
+            let top = item.layers.last().and_then(|l| l.split('/').next());
+            for (repo, tags) in &repositories {
+                for (tag, id) in tags {
+                    if Some(id.as_str()) == top {
+                        names.push(format!("{}:{}", repo, tag));
+                    }
+                }
+            }
+
+            let manifest = archive.resolve(&item)?;
+            for (layer, path) in manifest.layers.iter().zip(item.layers) {
+                blobs.insert(layer.digest.to_string(), path);
+            }
+
+            images.push(Image { names, manifest });
+        }
+
+        archive.images = Arc::new(images);
+        archive.blobs = Arc::new(blobs);
+        Ok(archive)
+    }
+
+    /// Opens a file in the tarball
+    fn section(&self, path: &str) -> Result<(u64, Section)> {
+        let (offset, size) = self
+            .entries
+            .get(path)
+            .ok_or_else(|| anyhow!("{} not found in {}", path, self))?;
+
+        let section = Section {
+            file: self.file.clone(),
+            offset: *offset,
+            end: offset + size,
+        };
+
+        Ok((*size, section))
+    }
+
+    /// Reads a (small) file in the tarball
+    fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let (.., section) = self.section(path)?;
+        let mut data = Vec::new();
+        section.take(Manifest::MAX_SIZE).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Converts an image into a manifest, verifying its configuration
+    ///
+    /// The layers are identified by the digests in their name or, in older
+    /// tarballs, by the digests of the uncompressed layers (`diff_ids`).
+    fn resolve(&self, item: &Item) -> Result<V2> {
+        let data = self.read(&item.config)?;
+        let digest = Self::digest(&item.config).unwrap_or_else(|| Digest::sha256(&data));
+        digest
+            .verify(&data)
+            .with_context(|| format!("invalid {} in {}", item.config, self))?;
+
+        let config: Config = serde_json::from_slice(&data)
+            .with_context(|| format!("invalid {} in {}", item.config, self))?;
+
+        let diff_ids = &config.rootfs.diff_ids;
+        if diff_ids.len() != item.layers.len() {
+            return Err(anyhow!(
+                "layer count mismatch for {} in {}",
+                item.config,
+                self
+            ));
+        }
+
+        let mut layers = Vec::new();
+        for (path, diff_id) in item.layers.iter().zip(diff_ids) {
+            let (size, ..) = self.section(path)?;
+            layers.push(Layer {
+                media_type: Some(Self::LAYER.into()),
+                size,
+                digest: Self::digest(path).unwrap_or_else(|| diff_id.clone()),
+                urls: Vec::new(),
+            });
+        }
+
+        Ok(V2 {
+            schema_version: 2,
+            media_type: Some(Manifest::DOCKER_V2.into()),
+            config: Blob {
+                media_type: Some(Self::CONFIG.into()),
+                size: data.len() as u64,
+                digest,
+            },
+            layers,
+        })
+    }
+
+    /// Gets the manifest of an image by name (format: `name:tag`)
+    ///
+    /// Names match with or without the `docker.io/library/` prefix. An
+    /// empty name matches the only image in the tarball.
+    fn find(&self, name: &str) -> Result<Manifest> {
+        fn short(name: &str) -> &str {
+            let name = name.strip_prefix("docker.io/").unwrap_or(name);
+            name.strip_prefix("library/").unwrap_or(name)
+        }
+
+        let mut found = self.images.iter().filter(|image| {
+            let mut names = image.names.iter();
+            name.is_empty() || names.any(|n| short(n) == short(name))
+        });
+
+        match (found.next(), found.next()) {
+            (Some(image), None) => Ok(Manifest::DockerV2(image.manifest.clone())),
+            (Some(..), Some(..)) if name.is_empty() => Err(anyhow!(
+                "several images in {}; use docker-archive:PATH:NAME:TAG",
+                self
+            )),
+            (Some(..), Some(..)) => Err(anyhow!("several images named {} in {}", name, self)),
+            (None, ..) => Err(anyhow!("no image named {:?} in {}", name, self)),
+        }
+    }
+}
+
+impl Source for Archive {
+    fn manifest(&self, reference: &str, _digest: Option<&Digest>) -> Result<(Manifest, bool)> {
+        Ok((self.find(reference)?, false))
+    }
+
+    fn blob(&self, layer: &Layer) -> Result<(u64, Body)> {
+        let digest = &layer.digest;
+        let (len, section) = match self.blobs.get(&digest.to_string()) {
+            Some(path) => self.section(path)?,
+            None => return Err(anyhow!("{} not found in {}", digest, self)),
+        };
+
+        let body: Body = Box::new(section);
+        Ok((len, Box::new(Validator::new(body, digest.clone()))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Archive, Source};
+    use crate::formats::{Digest, Manifest};
+
+    use std::io::{Read, Write};
+    use std::os::unix::io::FromRawFd;
+
+    const LAYER: &[u8] = b"not really a tarball";
+
+    fn append(tar: &mut tar::Builder<impl Write>, path: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, path, data).unwrap();
+    }
+
+    /// Writes a tarball like `docker save`, with `manifest.json` last
+    fn archive(writer: impl Write) {
+        let diff_id = Digest::sha256(LAYER);
+        let config = format!(
+            r#"{{"rootfs": {{"type": "layers", "diff_ids": ["{}"]}}}}"#,
+            diff_id
+        );
+        let id = Digest::sha256(config.as_bytes()).to_string()[7..].to_string();
+
+        let mut tar = tar::Builder::new(writer);
+        append(&mut tar, &format!("{}.json", id), config.as_bytes());
+        append(&mut tar, "0123/layer.tar", LAYER);
+        append(
+            &mut tar,
+            "repositories",
+            br#"{"example.com/debian": {"v1": "0123"}}"#,
+        );
+        append(
+            &mut tar,
+            "manifest.json",
+            format!(r#"[{{"Config": "{}.json", "RepoTags": ["debian:stable"], "Layers": ["0123/layer.tar"]}}]"#, id).as_bytes(),
+        );
+        tar.finish().unwrap();
+    }
+
+    /// Reads the only layer of the only image
+    fn layer(archive: &Archive) -> Vec<u8> {
+        let layers = match archive.find("").unwrap() {
+            Manifest::DockerV2(m) => m.layers,
+            m => panic!("unexpected manifest: {:?}", m),
+        };
+
+        let (size, mut reader) = archive.blob(&layers[0]).unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(size, data.len() as u64);
+        data
+    }
+
+    #[test]
+    fn open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.tar");
+        archive(std::fs::File::create(&path).unwrap());
+
+        let archive = Archive::open(path.to_str().unwrap()).unwrap();
+        let mut layers = Vec::new();
+        for name in [
+            "",
+            "debian:stable",
+            "docker.io/library/debian:stable",
+            "example.com/debian:v1",
+        ] {
+            match archive.find(name).unwrap() {
+                Manifest::DockerV2(m) => layers.extend(m.layers),
+                m => panic!("unexpected manifest: {:?}", m),
+            }
+        }
+
+        assert!(archive.find("debian:latest").is_err());
+        assert!(layers.iter().all(|l| l.digest == Digest::sha256(LAYER)));
+        assert_eq!(layer(&archive), LAYER);
+    }
+
+    #[test]
+    fn pipe() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (rx, tx) = unsafe {
+            (
+                std::fs::File::from_raw_fd(fds[0]),
+                std::fs::File::from_raw_fd(fds[1]),
+            )
+        };
+
+        let writer = std::thread::spawn(move || archive(tx));
+        let archive = Archive::open(&format!("/proc/self/fd/{}", fds[0])).unwrap();
+        writer.join().unwrap();
+        drop(rx);
+
+        assert_eq!(layer(&archive), LAYER);
+    }
+}
@@ -0,0 +1,211 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+//! Registry credentials
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use serde::Deserialize;
+
+/// A username and password for a registry
+///
+/// The password is never displayed, not even in debug output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    password: String,
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+impl FromStr for Credentials {
+    type Err = anyhow::Error;
+
+    /// Parses credentials (format: `USER:PASS`)
+    ///
+    /// Usernames cannot contain a colon, but passwords can.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some((username, password)) if !username.is_empty() => Ok(Self {
+                username: username.into(),
+                password: password.into(),
+            }),
+
+            _ => Err(anyhow!("invalid credentials (format: USER:PASS)")),
+        }
+    }
+}
+
+impl Credentials {
+    /// The value of an `Authorization` header using HTTP Basic
+    pub fn basic(&self) -> String {
+        let pair = format!("{}:{}", self.username, self.password);
+        format!("Basic {}", base64::encode(pair))
+    }
+}
+
+/// Registry credentials, by host
+#[derive(Clone, Debug, Default)]
+pub struct Auths(HashMap<String, Credentials>);
+
+impl Auths {
+    /// Names under which Docker Hub credentials are stored
+    const DOCKER_HUB: &'static [&'static str] = &[
+        "docker.io",
+        "index.docker.io",
+        "registry-1.docker.io",
+        "registry.hub.docker.com",
+    ];
+
+    /// Normalizes keys like `https://index.docker.io/v1/` to a host
+    fn host(key: &str) -> &str {
+        let key = key.split_once("://").map(|(.., x)| x).unwrap_or(key);
+        let key = key.split('/').next().unwrap_or(key);
+
+        match Self::DOCKER_HUB.contains(&key) {
+            true => Self::DOCKER_HUB[0],
+            false => key,
+        }
+    }
+
+    /// The default auth files, in order of precedence
+    fn defaults() -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+
+        if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+            paths.push(Path::new(&dir).join("containers/auth.json"));
+        }
+
+        if let Some(dir) = std::env::var_os("HOME") {
+            paths.push(Path::new(&dir).join(".docker/config.json"));
+        }
+
+        paths
+    }
+
+    /// Loads credentials from the default Docker and Podman auth files
+    ///
+    /// Missing or unreadable default files are skipped.
+    pub fn load(&mut self) {
+        for path in Self::defaults().into_iter().filter(|p| p.exists()) {
+            if let Err(e) = self.read(&path) {
+                warn!("skipping auth file {:?}: {:#}", path, e);
+            }
+        }
+    }
+
+    /// Reads credentials from a Docker `config.json` or Podman `auth.json`
+    ///
+    /// Existing credentials take precedence over those in the file.
+    pub fn read(&mut self, path: &Path) -> Result<()> {
+        #[derive(Deserialize)]
+        struct Entry {
+            auth: Option<String>,
+            username: Option<String>,
+            password: Option<String>,
+        }
+
+        #[derive(Deserialize)]
+        struct File {
+            #[serde(default)]
+            auths: HashMap<String, Entry>,
+        }
+
+        let file: File = serde_json::from_slice(&std::fs::read(path)?)
+            .with_context(|| format!("invalid auth file: {:?}", path))?;
+
+        for (key, entry) in file.auths {
+            let credentials = match entry {
+                Entry {
+                    auth: Some(auth), ..
+                } => base64::decode(auth.trim())
+                    .ok()
+                    .and_then(|auth| String::from_utf8(auth).ok())
+                    .and_then(|auth| auth.parse().ok())
+                    .ok_or_else(|| anyhow!("invalid auth for {} in {:?}", key, path))?,
+
+                Entry {
+                    username: Some(username),
+                    password: Some(password),
+                    ..
+                } => Credentials { username, password },
+
+                // Entries for credential helpers carry no credentials.
+                _ => continue,
+            };
+
+            self.insert(&key, credentials);
+        }
+
+        Ok(())
+    }
+
+    /// Adds credentials for a host, unless it already has some
+    pub fn insert(&mut self, host: &str, credentials: Credentials) {
+        self.0.entry(Self::host(host).into()).or_insert(credentials);
+    }
+
+    /// Gets the credentials for a host
+    pub fn get(&self, host: &str) -> Option<&Credentials> {
+        self.0.get(Self::host(host))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Auths, Credentials};
+
+    use std::io::Write;
+
+    #[test]
+    fn credentials() {
+        let creds: Credentials = "user:pa:ss@word".parse().unwrap();
+        assert_eq!(creds.username, "user");
+        assert_eq!(creds.basic(), "Basic dXNlcjpwYTpzc0B3b3Jk");
+        assert!(!format!("{:?}", creds).contains("pa:ss"));
+
+        assert!("user".parse::<Credentials>().is_err());
+        assert!(":pass".parse::<Credentials>().is_err());
+    }
+
+    #[test]
+    fn read() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(
+            r#"{
+                "auths": {
+                    "https://index.docker.io/v1/": { "auth": "aHViOnNlY3JldA==" },
+                    "registry.gitlab.com": { "username": "lab", "password": "pass" },
+                    "quay.io": {}
+                },
+                "credsStore": "desktop"
+            }"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let mut auths = Auths::default();
+        auths.insert("quay.io", "first:wins".parse().unwrap());
+        auths.read(file.path()).unwrap();
+
+        let hub = auths.get("registry.hub.docker.com").unwrap();
+        assert_eq!(hub, &"hub:secret".parse().unwrap());
+        let lab = auths.get("registry.gitlab.com").unwrap();
+        assert_eq!(lab, &"lab:pass".parse().unwrap());
+        let quay = auths.get("quay.io").unwrap();
+        assert_eq!(quay, &"first:wins".parse().unwrap());
+        assert!(auths.get("ghcr.io").is_none());
+    }
+}
@@ -0,0 +1,279 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+//! Resumable blob downloads
+
+use super::{Repository, Retry};
+
+use std::io::{Error, ErrorKind, Read};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use ureq::Response;
+
+/// A boxed reader of (the rest of) a blob
+pub(super) type Body = Box<dyn Read + Send>;
+
+/// A way to (re)connect to a blob at an offset
+pub(super) type Open = Box<dyn FnMut(u64) -> Result<Body> + Send>;
+
+/// Requests a blob from an offset, using a range request
+///
+/// If the server ignores the range, the bytes before the offset are
+/// skipped instead.
+fn ranged(
+    name: &str,
+    offset: u64,
+    get: impl FnOnce(&[(&str, &str)]) -> Result<Response>,
+) -> Result<Body> {
+    if offset == 0 {
+        return Ok(Box::new(get(&[])?.into_reader()));
+    }
+
+    let range = format!("bytes={}-", offset);
+    let rep = get(&[("Range", &range)])?;
+
+    if rep.status() == 206 {
+        let expected = format!("bytes {}-", offset);
+        return match rep.header("Content-Range") {
+            Some(x) if x.starts_with(&expected) => Ok(Box::new(rep.into_reader())),
+            x => Err(anyhow!("unexpected range for {}: {:?}", name, x)),
+        };
+    }
+
+    let mut reader = rep.into_reader();
+    let skipped = std::io::copy(&mut (&mut reader).take(offset), &mut std::io::sink())?;
+    match skipped == offset {
+        true => Ok(Box::new(reader)),
+        false => Err(anyhow!("{} ended before byte {}", name, offset)),
+    }
+}
+
+/// Gets a blob from the registry, from an offset
+///
+/// The request is not retried: `Resume` retries reconnections itself.
+pub(super) fn get(repo: &Repository, path: &str, offset: u64) -> Result<Body> {
+    ranged(path, offset, |headers| repo.once(path, headers))
+}
+
+/// Gets a blob from a URL outside of the registry, from an offset
+pub(super) fn external(repo: &Repository, url: &str, offset: u64) -> Result<Body> {
+    ranged(url, offset, |headers| repo.external(url, headers))
+}
+
+/// A reader that reconnects where it left off after errors
+///
+/// Since reconnecting continues the same stream of bytes, any validator
+/// wrapping this reader sees the blob exactly once.
+pub(super) struct Resume<F> {
+    name: String,
+    open: F,
+    retry: Retry,
+    deadline: Option<Instant>,
+    attempt: u32,
+    offset: u64,
+    size: Option<u64>,
+    reader: Body,
+}
+
+impl<F: FnMut(u64) -> Result<Body>> Resume<F> {
+    /// Creates a reader from the first connection and a way to reconnect
+    ///
+    /// If the size is known, ending early is treated like an error. No
+    /// reconnection is attempted past the deadline.
+    pub fn new(
+        name: String,
+        reader: Body,
+        size: Option<u64>,
+        retry: Retry,
+        deadline: Option<Instant>,
+        open: F,
+    ) -> Self {
+        Self {
+            name,
+            open,
+            retry,
+            deadline,
+            attempt: 0,
+            offset: 0,
+            size,
+            reader,
+        }
+    }
+
+    /// Reconnects at the current offset, retrying with backoff
+    fn reconnect(&mut self, mut error: Error) -> std::io::Result<()> {
+        while self.attempt < self.retry.attempts {
+            let delay = self.retry.delay(self.attempt);
+            self.attempt += 1;
+
+            if let Some(deadline) = self.deadline {
+                if Instant::now() + delay >= deadline {
+                    let msg = format!("deadline exceeded for {}: {}", self.name, error);
+                    return Err(Error::new(ErrorKind::TimedOut, msg));
+                }
+            }
+
+            warn!(
+                "resuming {} at byte {} in {:?}: {}",
+                self.name, self.offset, delay, error
+            );
+            std::thread::sleep(delay);
+
+            match (self.open)(self.offset) {
+                Ok(reader) => {
+                    self.reader = reader;
+                    return Ok(());
+                }
+
+                Err(e) => error = Error::other(format!("{:#}", e)),
+            }
+        }
+
+        Err(error)
+    }
+}
+
+impl<F: FnMut(u64) -> Result<Body>> Read for Resume<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let error = match self.reader.read(buf) {
+                Ok(0) if !buf.is_empty() && self.size.is_some_and(|size| self.offset < size) => {
+                    Error::new(ErrorKind::UnexpectedEof, "connection closed early")
+                }
+
+                Ok(size) => {
+                    // Only consecutive failures count towards the limit.
+                    if size > 0 {
+                        self.attempt = 0;
+                    }
+
+                    self.offset += size as u64;
+                    return Ok(size);
+                }
+
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+
+            self.reconnect(error)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Body, Resume};
+    use crate::api::Retry;
+
+    use std::io::{Error, ErrorKind, Read};
+    use std::time::{Duration, Instant};
+
+    use anyhow::anyhow;
+
+    const BLOB: &[u8] = b"0123456789abcdefghij";
+
+    /// Serves the blob from an offset, failing after a few bytes
+    fn flaky(offset: u64, fail: usize) -> Body {
+        let data = &BLOB[offset as usize..];
+        let (head, ..) = data.split_at(fail.min(data.len()));
+        let error = Error::new(ErrorKind::ConnectionReset, "reset");
+        let error = std::iter::once(Err(error)).filter(move |_| data.len() > fail);
+
+        // Yield each byte separately, then the error.
+        let chunks = head.iter().map(|b| Ok(vec![*b])).chain(error);
+        Box::new(Chunks(Box::new(chunks)))
+    }
+
+    type Iter = Box<dyn Iterator<Item = std::io::Result<Vec<u8>>> + Send>;
+    struct Chunks(Iter);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.next() {
+                None => Ok(0),
+                Some(Err(e)) => Err(e),
+                Some(Ok(chunk)) => {
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+            }
+        }
+    }
+
+    fn retry(attempts: u32) -> Retry {
+        Retry {
+            attempts,
+            backoff: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn resume() {
+        let mut offsets = Vec::new();
+        let open = |offset| {
+            offsets.push(offset);
+            match offset {
+                // The first reconnection fails outright.
+                7 if offsets.len() == 1 => Err(anyhow!("refused")),
+                _ => Ok(flaky(offset, 7)),
+            }
+        };
+
+        let mut reader = Resume::new("blob".into(), flaky(0, 7), None, retry(2), None, open);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        drop(reader);
+
+        // Every byte arrives once, despite three reconnections.
+        assert_eq!(data, BLOB);
+        assert_eq!(offsets, [7, 7, 14]);
+    }
+
+    #[test]
+    fn exhausted() {
+        let open = |offset| Ok(flaky(offset, 0));
+        let mut reader = Resume::new("blob".into(), flaky(0, 5), None, retry(3), None, open);
+
+        let mut data = Vec::new();
+        let error = reader.read_to_end(&mut data).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+        assert_eq!(data, &BLOB[..5]);
+    }
+
+    #[test]
+    fn truncated() {
+        let size = Some(BLOB.len() as u64);
+        let open = |offset: u64| Ok(Box::new(&BLOB[offset as usize..]) as Body);
+        let mut reader = Resume::new(
+            "blob".into(),
+            Box::new(&BLOB[..3]),
+            size,
+            retry(1),
+            None,
+            open,
+        );
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, BLOB);
+    }
+
+    #[test]
+    fn deadline() {
+        let mut opened = 0;
+        let open = |offset| {
+            opened += 1;
+            Ok(flaky(offset, 5))
+        };
+
+        let deadline = Some(Instant::now());
+        let mut reader = Resume::new("blob".into(), flaky(0, 5), None, retry(3), deadline, open);
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        drop(reader);
+
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert_eq!(opened, 0);
+    }
+}
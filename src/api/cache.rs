@@ -0,0 +1,573 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+//! A content-addressed blob cache (format: OCI image layout `blobs/`)
+
+use crate::formats::oci::{Descriptor, Index, REF_NAME};
+use crate::formats::{Digest, Manifest};
+use crate::iotools::{Siphon, Validator};
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+
+/// When to use the cache instead of the registry
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// Use the registry, falling back to the cache when it fails
+    #[default]
+    PreferNetwork,
+
+    /// Use the cache, falling back to the registry for anything missing
+    PreferCache,
+
+    /// Never contact the registry
+    CacheOnly,
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "prefer-network" => Ok(Self::PreferNetwork),
+            "prefer-cache" => Ok(Self::PreferCache),
+            "cache-only" => Ok(Self::CacheOnly),
+            _ => Err(anyhow!("invalid cache policy: {}", s)),
+        }
+    }
+}
+
+/// A blob in the cache
+#[derive(Clone, Debug)]
+pub struct Blob {
+    pub digest: Digest,
+    pub size: u64,
+
+    /// When the blob was last stored or read
+    pub used: SystemTime,
+}
+
+/// A directory of blobs, stored by digest
+#[derive(Clone, Debug)]
+pub struct Cache(PathBuf);
+
+impl Cache {
+    /// The prefix of blobs that are still being downloaded
+    const TEMP: &'static str = ".tmp-";
+
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self(dir.into())
+    }
+
+    /// The directory of blobs with an algorithm
+    fn blobs(&self, algorithm: &str) -> PathBuf {
+        self.0.join("blobs").join(algorithm)
+    }
+
+    /// The path of a blob (format: `blobs/ALGORITHM/HEX`)
+    fn path(&self, digest: &Digest) -> PathBuf {
+        let digest = digest.to_string();
+        let (algorithm, hex) = digest.split_once(':').unwrap_or_default();
+        self.blobs(algorithm).join(hex)
+    }
+
+    /// Opens a cached blob, if present
+    ///
+    /// The blob is verified before it is returned. Damaged blobs are removed
+    /// and reported as missing, so that they are downloaded again. Blobs that
+    /// are opened are marked as used, so that pruning keeps them longer.
+    pub fn open(&self, digest: &Digest) -> Result<Option<(u64, File)>> {
+        let path = self.path(digest);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut validator = Validator::new(&mut file, digest.clone());
+        if let Err(e) = std::io::copy(&mut validator, &mut std::io::sink()) {
+            warn!("removing damaged blob {:?}: {}", path, e);
+            std::fs::remove_file(&path)?;
+            return Ok(None);
+        }
+
+        file.rewind()?;
+
+        if let Err(e) = file.set_modified(SystemTime::now()) {
+            warn!("unable to mark {:?} as used: {}", path, e);
+        }
+
+        Ok(Some((file.metadata()?.len(), file)))
+    }
+
+    /// Whether a blob is in the cache, without checking it
+    pub(super) fn contains(&self, digest: &Digest) -> bool {
+        self.path(digest).exists()
+    }
+
+    /// Starts storing a blob
+    pub(super) fn entry(&self, digest: &Digest) -> Result<Entry> {
+        // Concurrent downloads of the same blob get separate files.
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = self.path(digest);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let temp = format!("{}{}-{}-{}", Self::TEMP, name, std::process::id(), n);
+        let temp = path.with_file_name(temp);
+
+        std::fs::create_dir_all(path.parent().unwrap_or(&self.0))?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+
+        Ok(Entry {
+            file: Some(file),
+            temp,
+            path,
+        })
+    }
+
+    /// Reads `index.json`
+    fn index(&self) -> Result<Index> {
+        let path = self.0.join("index.json");
+        match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("invalid cache index: {:?}", path)),
+
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Index {
+                schema_version: 2,
+                media_type: Some(Manifest::OCI_INDEX.into()),
+                manifests: Vec::new(),
+                annotations: Default::default(),
+            }),
+
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Atomically updates `index.json`
+    fn update(&self, func: impl FnOnce(&mut Index)) -> Result<()> {
+        // Refreshing in the background may store manifests concurrently.
+        static LOCK: Mutex<()> = Mutex::new(());
+
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut index = self.index()?;
+        func(&mut index);
+
+        self.replace("oci-layout", br#"{"imageLayoutVersion":"1.0.0"}"#)?;
+        self.replace("index.json", &serde_json::to_vec(&index)?)
+    }
+
+    /// Makes the renames in a directory durable
+    fn sync(dir: &Path) -> std::io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+
+    /// Atomically replaces a small file in the cache directory
+    fn replace(&self, name: &str, data: &[u8]) -> Result<()> {
+        let temp = self
+            .0
+            .join(format!("{}{}-{}", Self::TEMP, name, std::process::id()));
+
+        let result = File::create(&temp)
+            .and_then(|mut file| file.write_all(data).and_then(|_| file.sync_all()))
+            .and_then(|_| std::fs::rename(&temp, self.0.join(name)))
+            .and_then(|_| Self::sync(&self.0));
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+
+        Ok(result?)
+    }
+
+    /// Reads a cached manifest by digest or, failing that, by name
+    ///
+    /// Names are full references (format: `host/path:tag`).
+    pub(super) fn manifest(&self, name: &str, digest: Option<&Digest>) -> Result<Option<Vec<u8>>> {
+        let digest = match digest {
+            Some(digest) => digest.clone(),
+            None => match self
+                .index()?
+                .manifests
+                .iter()
+                .find(|d| d.name() == Some(name))
+            {
+                Some(found) => found.digest.clone(),
+                None => return Ok(None),
+            },
+        };
+
+        let mut body = Vec::new();
+        match self.open(&digest)? {
+            Some((.., mut file)) => {
+                file.read_to_end(&mut body)?;
+                Ok(Some(body))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Stores a manifest and, if it was pulled by tag, names it
+    ///
+    /// The name replaces any earlier manifest of the same name.
+    pub(super) fn store(
+        &self,
+        name: Option<&str>,
+        media_type: &str,
+        digest: &Digest,
+        body: &[u8],
+    ) -> Result<()> {
+        digest.verify(body)?;
+        let mut entry = self.entry(digest)?;
+        entry.write_all(body)?;
+        entry.commit()?;
+
+        let name = match name {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+
+        self.update(|index| {
+            index.manifests.retain(|d| d.name() != Some(name));
+            index.manifests.push(Descriptor {
+                media_type: media_type.into(),
+                digest: digest.clone(),
+                size: body.len() as u64,
+                urls: Vec::new(),
+                annotations: [(REF_NAME.into(), name.into())].into(),
+                platform: None,
+            });
+        })
+    }
+
+    /// Lists the cached blobs, and the paths of any temporary files
+    fn scan(&self) -> Result<(Vec<Blob>, Vec<PathBuf>)> {
+        let mut blobs = Vec::new();
+        let mut temps = Vec::new();
+
+        let algorithms = match std::fs::read_dir(self.0.join("blobs")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok((blobs, temps)),
+            Err(e) => return Err(e.into()),
+        };
+
+        for algorithm in algorithms {
+            let algorithm = algorithm?.file_name();
+            let algorithm = algorithm.to_string_lossy();
+
+            for entry in std::fs::read_dir(self.blobs(&algorithm))? {
+                let entry = entry?;
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+
+                if name.starts_with(Self::TEMP) {
+                    temps.push(entry.path());
+                    continue;
+                }
+
+                match format!("{}:{}", algorithm, name).parse() {
+                    Ok(digest) => {
+                        let meta = entry.metadata()?;
+                        blobs.push(Blob {
+                            digest,
+                            size: meta.len(),
+                            used: meta.modified()?,
+                        });
+                    }
+
+                    Err(..) => warn!("skipping unknown file in cache: {:?}", entry.path()),
+                }
+            }
+        }
+
+        blobs.sort_by_key(|blob| blob.used);
+        Ok((blobs, temps))
+    }
+
+    /// Lists the cached blobs, from least to most recently used
+    pub fn list(&self) -> Result<Vec<Blob>> {
+        Ok(self.scan()?.0)
+    }
+
+    /// Removes temporary files, blobs unused for longer than `max_age` and
+    /// the least recently used blobs until the cache fits into `max_size`
+    ///
+    /// Names of removed manifests are removed from `index.json` as well.
+    /// Returns the removed blobs.
+    pub fn prune(&self, max_age: Option<Duration>, max_size: Option<u64>) -> Result<Vec<Blob>> {
+        let (blobs, temps) = self.scan()?;
+
+        for temp in temps {
+            std::fs::remove_file(temp)?;
+        }
+
+        let now = SystemTime::now();
+        let mut total: u64 = blobs.iter().map(|blob| blob.size).sum();
+        let mut removed = Vec::new();
+
+        for blob in blobs {
+            let age = now.duration_since(blob.used).unwrap_or_default();
+            let old = max_age.is_some_and(|max| age > max);
+            let full = max_size.is_some_and(|max| total > max);
+            if !old && !full {
+                continue;
+            }
+
+            std::fs::remove_file(self.path(&blob.digest))?;
+            total -= blob.size;
+            removed.push(blob);
+        }
+
+        let gone = |d: &Descriptor| removed.iter().any(|blob| blob.digest == d.digest);
+        if self.index()?.manifests.iter().any(gone) {
+            self.update(|index| index.manifests.retain(|d| !gone(d)))?;
+        }
+
+        Ok(removed)
+    }
+}
+
+/// A blob being stored in the cache
+///
+/// The cache is only an optimization, so failing to write to it does not
+/// fail the download. The blob is just not committed.
+#[derive(Debug)]
+pub(super) struct Entry {
+    file: Option<File>,
+    temp: PathBuf,
+    path: PathBuf,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        // Nothing is left after a commit.
+        let _ = std::fs::remove_file(&self.temp);
+    }
+}
+
+impl Write for Entry {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.write_all(buf) {
+                warn!("unable to cache {:?}: {}", self.path, e);
+                self.file = None;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Entry {
+    /// Atomically moves the complete blob into place
+    fn commit(&self) -> Result<()> {
+        let file = self
+            .file
+            .as_ref()
+            .ok_or_else(|| anyhow!("incomplete blob: {:?}", self.path))?;
+
+        file.sync_all()?;
+        std::fs::rename(&self.temp, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            Cache::sync(dir)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A validated download that is stored in the cache as it is read
+///
+/// The blob is only committed once the validator has verified it.
+pub struct Fill<R: Read>(Validator<Siphon<R, Entry>, Digest>, bool);
+
+impl<R: Read> Fill<R> {
+    pub(super) fn new(reader: R, entry: Entry, digest: Digest) -> Self {
+        Self(Validator::new(Siphon::new(reader, entry), digest), false)
+    }
+}
+
+impl<R: Read> Read for Fill<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.0.read(buf)?;
+
+        if size == 0 && !buf.is_empty() && !self.1 {
+            self.1 = true;
+
+            let entry = self.0.reader().writer();
+            if let Err(e) = entry.commit() {
+                warn!("unable to cache {:?}: {:#}", entry.path, e);
+            }
+        }
+
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cache, Fill, Policy};
+    use crate::formats::Digest;
+
+    use std::io::Read;
+    use std::time::{Duration, SystemTime};
+
+    const EMPTY: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const HELLO: &str = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn fill(cache: &Cache, digest: &str, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let digest: Digest = digest.parse().unwrap();
+        let entry = cache.entry(&digest).unwrap();
+        let mut out = Vec::new();
+        Fill::new(data, entry, digest).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let cache = Cache::new(dir);
+        assert!(cache.list().unwrap().is_empty());
+
+        // Invalid blobs are never committed.
+        assert!(fill(&cache, HELLO, b"goodbye").is_err());
+        assert!(cache.open(&HELLO.parse().unwrap()).unwrap().is_none());
+
+        assert_eq!(fill(&cache, HELLO, b"hello").unwrap(), b"hello");
+        assert_eq!(fill(&cache, EMPTY, b"").unwrap(), b"");
+
+        let (size, mut hit) = cache.open(&HELLO.parse().unwrap()).unwrap().unwrap();
+        let mut data = Vec::new();
+        hit.read_to_end(&mut data).unwrap();
+        assert_eq!((size, &data[..]), (5, &b"hello"[..]));
+        assert!(dir.join("blobs/sha256").join(&HELLO[7..]).exists());
+
+        // The most recently used blob is listed last.
+        let listed: Vec<String> = cache
+            .list()
+            .unwrap()
+            .iter()
+            .map(|b| b.digest.to_string())
+            .collect();
+        assert_eq!(listed, [EMPTY, HELLO]);
+
+        // Damaged blobs are removed when opened.
+        let path = dir.join("blobs/sha256").join(&EMPTY[7..]);
+        std::fs::write(&path, b"damaged").unwrap();
+        assert!(cache.open(&EMPTY.parse().unwrap()).unwrap().is_none());
+        assert!(!path.exists());
+        fill(&cache, EMPTY, b"").unwrap();
+
+        // Temporary files are always pruned.
+        let entry = cache.entry(&HELLO.parse().unwrap()).unwrap();
+        std::mem::forget(entry);
+        assert_eq!(
+            std::fs::read_dir(dir.join("blobs/sha256")).unwrap().count(),
+            3
+        );
+        assert!(cache.prune(None, None).unwrap().is_empty());
+        assert_eq!(
+            std::fs::read_dir(dir.join("blobs/sha256")).unwrap().count(),
+            2
+        );
+
+        // Old blobs, then the least recently used blobs, are pruned.
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        let path = dir.join("blobs/sha256").join(&EMPTY[7..]);
+        std::fs::File::open(&path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        let removed = cache.prune(Some(Duration::from_secs(60)), None).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].digest.to_string(), EMPTY);
+
+        assert!(cache.prune(None, Some(5)).unwrap().is_empty());
+        assert_eq!(cache.prune(None, Some(4)).unwrap().len(), 1);
+        assert!(cache.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let cache = Cache::new(dir);
+        let name = "registry.lan/debian:latest";
+        let hello = HELLO.parse().unwrap();
+        assert!(cache.manifest(name, None).unwrap().is_none());
+
+        cache
+            .store(Some(name), "text/plain", &hello, b"hello")
+            .unwrap();
+        assert_eq!(cache.manifest(name, None).unwrap().unwrap(), b"hello");
+        assert_eq!(
+            cache.manifest("x", Some(&hello)).unwrap().unwrap(),
+            b"hello"
+        );
+        assert!(dir.join("oci-layout").exists());
+
+        // Names move to the latest manifest.
+        let empty = EMPTY.parse().unwrap();
+        cache.store(Some(name), "text/plain", &empty, b"").unwrap();
+        cache.store(None, "text/plain", &hello, b"hello").unwrap();
+        assert_eq!(cache.manifest(name, None).unwrap().unwrap(), b"");
+        assert_eq!(cache.index().unwrap().manifests.len(), 1);
+
+        // Only intact manifests are stored.
+        assert!(cache.store(None, "text/plain", &hello, b"bogus").is_err());
+        assert!(cache.manifest(name, Some(&hello)).unwrap().is_some());
+
+        // Pruning a manifest removes its name.
+        let other = "registry.lan/debian:stable";
+        cache
+            .store(Some(other), "text/plain", &hello, b"hello")
+            .unwrap();
+        let path = dir.join("blobs/sha256").join(&EMPTY[7..]);
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::open(&path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        assert_eq!(
+            cache
+                .prune(Some(Duration::from_secs(60)), None)
+                .unwrap()
+                .len(),
+            1
+        );
+        let index = cache.index().unwrap();
+        let names: Vec<_> = index.manifests.iter().map(|d| d.name()).collect();
+        assert_eq!(names, [Some(other)]);
+        assert!(cache.manifest(name, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn policy() {
+        assert_eq!(
+            "prefer-network".parse::<Policy>().unwrap(),
+            Policy::default()
+        );
+        assert_eq!(
+            "prefer-cache".parse::<Policy>().unwrap(),
+            Policy::PreferCache
+        );
+        assert_eq!("cache-only".parse::<Policy>().unwrap(), Policy::CacheOnly);
+        assert!("never".parse::<Policy>().is_err());
+    }
+}
@@ -0,0 +1,325 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+//! Parsing of `WWW-Authenticate` challenges (RFC 7235, section 4.1)
+
+use anyhow::{anyhow, Result};
+
+/// A single authentication challenge
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Challenge {
+    /// The authentication scheme, in lowercase
+    pub scheme: String,
+
+    /// The token68 form of credentials, if used instead of parameters
+    pub token68: Option<String>,
+
+    /// The parameters, with names in lowercase
+    pub params: Vec<(String, String)>,
+}
+
+impl Challenge {
+    /// Gets the value of a parameter
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, ..)| k.eq_ignore_ascii_case(name))
+            .map(|(.., v)| v.as_str())
+    }
+
+    /// Parses all challenges in a header value
+    pub fn parse(header: &str) -> Result<Vec<Self>> {
+        Parser(header.as_bytes(), 0).challenges()
+    }
+}
+
+/// A cursor over a header value
+struct Parser<'a>(&'a [u8], usize);
+
+impl<'a> Parser<'a> {
+    fn tchar(b: u8) -> bool {
+        b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+    }
+
+    fn t68char(b: u8) -> bool {
+        b.is_ascii_alphanumeric() || b"-._~+/".contains(&b)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.0.get(self.1).copied()
+    }
+
+    fn error(&self, msg: &str) -> anyhow::Error {
+        let header = String::from_utf8_lossy(self.0);
+        anyhow!(
+            "invalid authentication challenge ({} at {}): {}",
+            msg,
+            self.1,
+            header
+        )
+    }
+
+    /// Skips optional whitespace
+    fn ows(&mut self) {
+        while let Some(b' ' | b'\t') = self.peek() {
+            self.1 += 1;
+        }
+    }
+
+    /// Skips whitespace and (empty) list elements
+    fn separators(&mut self) {
+        while let Some(b' ' | b'\t' | b',') = self.peek() {
+            self.1 += 1;
+        }
+    }
+
+    fn token(&mut self) -> Option<&'a str> {
+        let start = self.1;
+        while self.peek().is_some_and(Self::tchar) {
+            self.1 += 1;
+        }
+
+        match self.1 > start {
+            true => std::str::from_utf8(&self.0[start..self.1]).ok(),
+            false => None,
+        }
+    }
+
+    fn token68(&mut self) -> Option<&'a str> {
+        let start = self.1;
+        while self.peek().is_some_and(Self::t68char) {
+            self.1 += 1;
+        }
+
+        if self.1 == start {
+            return None;
+        }
+
+        while self.peek() == Some(b'=') {
+            self.1 += 1;
+        }
+
+        std::str::from_utf8(&self.0[start..self.1]).ok()
+    }
+
+    fn quoted(&mut self) -> Result<String> {
+        let mut value = Vec::new();
+
+        self.1 += 1;
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated quoted string")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.1 += 1;
+                    match self.peek() {
+                        Some(b) => value.push(b),
+                        None => return Err(self.error("unterminated quoted string")),
+                    }
+                }
+                Some(b) => value.push(b),
+            }
+
+            self.1 += 1;
+        }
+
+        self.1 += 1;
+        String::from_utf8(value).map_err(|_| self.error("invalid UTF-8"))
+    }
+
+    /// Parses an `auth-param` value
+    fn value(&mut self) -> Result<String> {
+        self.ows();
+        match self.peek() {
+            Some(b'"') => self.quoted(),
+            _ => match self.token() {
+                Some(token) => Ok(token.into()),
+                None => Err(self.error("expected parameter value")),
+            },
+        }
+    }
+
+    /// Parses the next `auth-param`, if the next list element is one
+    ///
+    /// Otherwise, the position is left unchanged (i.e. at the next scheme).
+    fn param(&mut self) -> Result<Option<(String, String)>> {
+        let start = self.1;
+
+        if let Some(name) = self.token() {
+            self.ows();
+            if self.peek() == Some(b'=') {
+                self.1 += 1;
+                return Ok(Some((name.to_ascii_lowercase(), self.value()?)));
+            }
+        }
+
+        self.1 = start;
+        Ok(None)
+    }
+
+    fn challenge(&mut self) -> Result<Challenge> {
+        let scheme = self
+            .token()
+            .ok_or_else(|| self.error("expected authentication scheme"))?;
+
+        let mut challenge = Challenge {
+            scheme: scheme.to_ascii_lowercase(),
+            ..Default::default()
+        };
+
+        // The scheme must be followed by a space before any parameters.
+        match self.peek() {
+            None | Some(b',') => return Ok(challenge),
+            Some(b' ' | b'\t') => self.ows(),
+            Some(..) => return Err(self.error("expected whitespace")),
+        }
+
+        // A token68 is followed by the end of the challenge, unlike a token.
+        let start = self.1;
+        if let Some(token68) = self.token68() {
+            self.ows();
+            if let None | Some(b',') = self.peek() {
+                challenge.token68 = Some(token68.into());
+                return Ok(challenge);
+            }
+        }
+        self.1 = start;
+
+        loop {
+            match self.param()? {
+                Some(param) => challenge.params.push(param),
+                None => break,
+            }
+
+            self.ows();
+            match self.peek() {
+                None => break,
+                Some(b',') => self.separators(),
+                Some(..) => return Err(self.error("expected comma")),
+            }
+        }
+
+        Ok(challenge)
+    }
+
+    fn challenges(&mut self) -> Result<Vec<Challenge>> {
+        let mut challenges = Vec::new();
+
+        self.separators();
+        while self.peek().is_some() {
+            challenges.push(self.challenge()?);
+            self.separators();
+        }
+
+        match challenges.is_empty() {
+            true => Err(self.error("no challenges")),
+            false => Ok(challenges),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Challenge;
+
+    fn challenge(scheme: &str, params: &[(&str, &str)]) -> Challenge {
+        Challenge {
+            scheme: scheme.into(),
+            token68: None,
+            params: params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn registries() {
+        let hub = r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/debian:pull""#;
+        assert_eq!(
+            Challenge::parse(hub).unwrap(),
+            [challenge(
+                "bearer",
+                &[
+                    ("realm", "https://auth.docker.io/token"),
+                    ("service", "registry.docker.io"),
+                    ("scope", "repository:library/debian:pull"),
+                ]
+            )]
+        );
+
+        let ghcr = r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:wyrcan/debian:pull""#;
+        let parsed = Challenge::parse(ghcr).unwrap();
+        assert_eq!(parsed[0].param("realm"), Some("https://ghcr.io/token"));
+        assert_eq!(parsed[0].param("Service"), Some("ghcr.io"));
+
+        let quay = r#"Bearer realm="https://quay.io/v2/auth",service="quay.io",scope="repository:coreos/etcd:pull""#;
+        let parsed = Challenge::parse(quay).unwrap();
+        assert_eq!(
+            parsed[0].param("scope"),
+            Some("repository:coreos/etcd:pull")
+        );
+
+        let gitlab = r#"Bearer realm="https://gitlab.com/jwt/auth",service="container_registry",scope="repository:wyrcan/debian:pull,push",error="insufficient_scope""#;
+        let parsed = Challenge::parse(gitlab).unwrap();
+        assert_eq!(
+            parsed[0].param("scope"),
+            Some("repository:wyrcan/debian:pull,push")
+        );
+        assert_eq!(parsed[0].param("error"), Some("insufficient_scope"));
+
+        let basic = r#"Basic realm="Registry Realm""#;
+        assert_eq!(
+            Challenge::parse(basic).unwrap(),
+            [challenge("basic", &[("realm", "Registry Realm")])]
+        );
+    }
+
+    #[test]
+    fn rfc7235() {
+        let header = r#"Newauth realm="apps", type=1,
+                        title="Login to \"apps\"", Basic realm="simple""#;
+        let header = header.replace('\n', "");
+        assert_eq!(
+            Challenge::parse(&header).unwrap(),
+            [
+                challenge(
+                    "newauth",
+                    &[
+                        ("realm", "apps"),
+                        ("type", "1"),
+                        ("title", "Login to \"apps\"")
+                    ]
+                ),
+                challenge("basic", &[("realm", "simple")]),
+            ]
+        );
+
+        let parsed = Challenge::parse("Negotiate YIIB==, Bearer, , Basic").unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed[0].token68.as_deref(), Some("YIIB=="));
+        assert_eq!(parsed[1], challenge("bearer", &[]));
+        assert_eq!(parsed[2], challenge("basic", &[]));
+
+        let parsed = Challenge::parse("Bearer realm = x , scope=\"a,b\"").unwrap();
+        assert_eq!(
+            parsed,
+            [challenge("bearer", &[("realm", "x"), ("scope", "a,b")])]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        for header in [
+            "",
+            " , ",
+            "Bearer realm=\"unterminated",
+            "=realm",
+            "Bearer realm=x, scope=",
+            "Bearer realm=\"x\" scope=\"y\"",
+            "Bearer\"x\"",
+        ] {
+            assert!(Challenge::parse(header).is_err(), "{}", header);
+        }
+    }
+}
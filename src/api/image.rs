@@ -1,38 +1,95 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
-use super::Repository;
-use crate::formats::{docker::v2::Layer, Manifest};
+use super::{source, Config, Source};
+use crate::formats::docker::v2::{Layer, Platform};
+use crate::formats::Manifest;
 
 use std::fmt::Display;
+use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 #[derive(Clone, Debug)]
 pub struct Image {
-    repo: Repository,
+    source: Arc<dyn Source>,
     manifest: Manifest,
     tag: String,
+    cached: bool,
 }
 
 impl Display for Image {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{}", self.repo, self.tag)
+        match self.tag.is_empty() {
+            true => write!(f, "{}", self.source),
+            false => write!(f, "{}:{}", self.source, self.tag),
+        }
     }
 }
 
 impl Image {
-    pub(super) fn new(repo: Repository, tag: &str) -> Result<Self> {
-        let path = format!("manifests/{}", tag);
-        let rep = repo.get(&path, &[])?;
+    /// The maximum number of nested manifest lists to follow
+    const MAX_DEPTH: usize = 4;
+
+    /// Opens an image by name
+    ///
+    /// Names are either registry references, OCI image layout directories
+    /// (format: `oci:PATH[:REF]`) or `docker save` tarballs (format:
+    /// `docker-archive:PATH[:NAME:TAG]`). Without a reference, a layout or
+    /// tarball must contain one image (or, for layouts, one per platform).
+    pub fn open(name: &str, config: Arc<Config>, platform: &Platform) -> Result<Self> {
+        let (source, tag) = source::parse(name, config)?;
+        Self::new(source, tag, platform)
+    }
+
+    pub fn new(source: Arc<dyn Source>, tag: &str, platform: &Platform) -> Result<Self> {
+        let digest = source.resolve(tag)?;
+        let (mut manifest, cached) = source.manifest(tag, digest.as_ref())?;
+
+        // Resolve manifest lists and indexes to the requested platform.
+        for _ in 0..Self::MAX_DEPTH {
+            let digest = match &manifest {
+                Manifest::DockerV2List(list) => list
+                    .manifests
+                    .iter()
+                    .find(|item| platform.matches(&item.platform))
+                    .map(|item| item.digest.clone()),
+
+                Manifest::OciIndex(index) => index
+                    .manifests
+                    .iter()
+                    .find(|desc| desc.platform.as_ref().is_some_and(|p| platform.matches(p)))
+                    .map(|desc| desc.digest.clone()),
+
+                _ => break,
+            };
+
+            let digest = digest
+                .ok_or_else(|| anyhow!("no manifest for {} in {}:{}", platform, source, tag))?;
+            manifest = source.manifest(&digest.to_string(), Some(&digest))?.0;
+        }
+
+        if let Manifest::DockerV2List(..) | Manifest::OciIndex(..) = manifest {
+            return Err(anyhow!(
+                "too many nested manifest lists in {}:{}",
+                source,
+                tag
+            ));
+        }
 
         Ok(Image {
-            manifest: rep.into_json()?,
-            repo,
+            manifest,
+            source,
             tag: tag.into(),
+            cached,
         })
     }
 
+    /// Whether the (top-level) manifest was read from the cache
+    pub fn cached(&self) -> bool {
+        self.cached
+    }
+
     pub fn layers(&self) -> Result<Vec<super::Layer>> {
         const DEFAULT: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
 
@@ -42,7 +99,7 @@ impl Image {
                 .iter()
                 .map(|l| {
                     super::Layer::new(
-                        self.repo.clone(),
+                        self.source.clone(),
                         Layer {
                             media_type: Some(DEFAULT.into()),
                             size: 0,
@@ -57,17 +114,19 @@ impl Image {
                 .layers
                 .iter()
                 .cloned()
-                .map(|l| super::Layer::new(self.repo.clone(), l))
+                .map(|l| super::Layer::new(self.source.clone(), l))
                 .collect(),
 
-            Manifest::DockerV2List(..) => panic!(),
+            Manifest::DockerV2List(..) | Manifest::OciIndex(..) => {
+                return Err(anyhow!("unresolved manifest list: {}", self))
+            }
 
             Manifest::Oci(m) => m
                 .layers
                 .iter()
                 .map(|l| {
                     super::Layer::new(
-                        self.repo.clone(),
+                        self.source.clone(),
                         Layer {
                             media_type: Some(l.media_type.clone()),
                             size: l.size,
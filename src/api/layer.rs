@@ -1,64 +1,250 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
-use super::Repository;
+use super::Source;
 use crate::formats::docker::v2::Layer as Level;
-use crate::iotools::{Either, Validator};
+use crate::iotools::Either;
 
-use std::io::Read;
+use std::io::{BufRead, Chain, Cursor, Read};
+use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
-use flate2::read::GzDecoder;
+use bzip2::bufread::BzDecoder;
+use flate2::bufread::GzDecoder;
+use log::warn;
+use xz2::bufread::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// The decompressed stream of a layer
+pub type Decompressor<R> = Either<
+    GzDecoder<R>,
+    Either<ZstdDecoder<'static, R>, Either<XzDecoder<R>, Either<BzDecoder<R>, R>>>,
+>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Comp {
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+    None,
+}
+
+impl Comp {
+    const MAGIC: &'static [(&'static [u8], Comp)] = &[
+        (b"\x1f\x8b", Comp::Gzip),
+        (b"\x28\xb5\x2f\xfd", Comp::Zstd),
+        (b"\xfd7zXZ\x00", Comp::Xz),
+    ];
+
+    /// The magic of the first bzip2 block, which follows `BZh` and the block size
+    const BLOCK: &'static [u8] = b"\x31\x41\x59\x26\x53\x59";
+
+    /// The length of the longest magic (bzip2: `BZh`, a digit and the block)
+    const HEADER: u64 = 10;
+
+    /// Detects the compression from the magic bytes at the start of a blob
+    ///
+    /// Anything we don't recognize is assumed to be an uncompressed tarball.
+    fn sniff(head: &[u8]) -> Self {
+        // A bare `BZh` could just as well be the name of a file in a tarball.
+        if let [b'B', b'Z', b'h', b'1'..=b'9', block @ ..] = head {
+            if block.starts_with(Self::BLOCK) {
+                return Comp::Bzip2;
+            }
+        }
+
+        Self::MAGIC
+            .iter()
+            .find(|(magic, ..)| head.starts_with(magic))
+            .map(|(.., comp)| *comp)
+            .unwrap_or(Comp::None)
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Layer {
-    repo: Repository,
+    source: Arc<dyn Source>,
     level: Level,
 }
 
 impl Layer {
-    pub(super) fn new(repo: Repository, level: Level) -> Self {
-        Self { repo, level }
+    pub(super) fn new(source: Arc<dyn Source>, level: Level) -> Self {
+        Self { source, level }
     }
 
-    pub fn decompressor<R: Read>(&self, reader: R) -> Result<Either<GzDecoder<R>, R>> {
-        enum Comp {
-            Gzip,
-            None,
-        }
-
-        let comp = match self.level.media_type.as_deref() {
-            Some("application/vnd.docker.image.rootfs.diff.tar.gzip") => Comp::Gzip,
-            Some("application/vnd.docker.image.rootfs.diff.tar") => Comp::None,
+    pub fn decompressor<R: BufRead>(
+        &self,
+        mut reader: R,
+    ) -> Result<Decompressor<Chain<Cursor<Vec<u8>>, R>>> {
+        // The media type is only a hint: some registries mislabel blobs.
+        let hint = match self.level.media_type.as_deref() {
+            Some("application/vnd.docker.image.rootfs.diff.tar.gzip") => Some(Comp::Gzip),
+            Some("application/vnd.docker.image.rootfs.diff.tar") => Some(Comp::None),
+            Some("application/vnd.docker.image.rootfs.foreign.diff.tar.gzip") => Some(Comp::Gzip),
 
-            Some("application/vnd.oci.image.layer.nondistributable.v1.tar+gzip") => Comp::Gzip,
-            Some("application/vnd.oci.image.layer.nondistributable.v1.tar") => Comp::None,
+            Some("application/vnd.oci.image.layer.nondistributable.v1.tar+gzip") => {
+                Some(Comp::Gzip)
+            }
+            Some("application/vnd.oci.image.layer.nondistributable.v1.tar+zstd") => {
+                Some(Comp::Zstd)
+            }
+            Some("application/vnd.oci.image.layer.nondistributable.v1.tar") => Some(Comp::None),
 
-            Some("application/vnd.oci.image.layer.v1.tar+gzip") => Comp::Gzip,
-            Some("application/vnd.oci.image.layer.v1.tar") => Comp::None,
+            Some("application/vnd.oci.image.layer.v1.tar+gzip") => Some(Comp::Gzip),
+            Some("application/vnd.oci.image.layer.v1.tar+zstd") => Some(Comp::Zstd),
+            Some("application/vnd.oci.image.layer.v1.tar") => Some(Comp::None),
 
-            None => Comp::None,
+            None => None,
             kind => return Err(anyhow!("unkown layer type: {:?}", kind)),
         };
 
+        // Reads may be short, so read until we have the whole header.
+        let mut head = Vec::new();
+        reader.by_ref().take(Comp::HEADER).read_to_end(&mut head)?;
+        let comp = Comp::sniff(&head);
+        let reader = Cursor::new(head).chain(reader);
+
+        if let Some(hint) = hint.filter(|hint| *hint != comp) {
+            warn!(
+                "layer {} is labelled {:?} but looks like {:?}",
+                self.level.digest, hint, comp
+            );
+        }
+
         let x = match comp {
             Comp::Gzip => Either::One(GzDecoder::new(reader)),
-            Comp::None => Either::Two(reader),
+            Comp::Zstd => Either::Two(Either::One(ZstdDecoder::with_buffer(reader)?)),
+            Comp::Xz => Either::Two(Either::Two(Either::One(XzDecoder::new(reader)))),
+            Comp::Bzip2 => Either::Two(Either::Two(Either::Two(Either::One(BzDecoder::new(
+                reader,
+            ))))),
+            Comp::None => Either::Two(Either::Two(Either::Two(Either::Two(reader)))),
         };
 
         Ok(x)
     }
 
+    /// Whether the layer is in the cache, without checking it
+    pub fn cached(&self) -> bool {
+        self.source.cached(&self.level)
+    }
+
+    /// Opens the (compressed) blob, which is validated while read
     pub fn download(&self) -> Result<(u64, impl Read + Send)> {
-        let path = format!("blobs/{}", self.level.digest);
+        self.source.blob(&self.level)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Layer, Level};
+    use crate::api::source;
+
+    use std::io::{BufReader, Read, Write};
+
+    use tar::{Builder, Header};
+
+    const DIGEST: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn layer(media_type: Option<&str>) -> Layer {
+        let (source, ..) = source::parse("debian", Default::default()).unwrap();
+        let level = Level {
+            media_type: media_type.map(Into::into),
+            size: 0,
+            digest: DIGEST.parse().unwrap(),
+            urls: Vec::new(),
+        };
+
+        Layer::new(source, level)
+    }
+
+    fn decompress(media_type: Option<&str>, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut reader = layer(media_type).decompressor(data).unwrap();
+        reader.read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn decompressor() {
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        gzip.write_all(b"layer").unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let zstd = zstd::encode_all(&b"layer"[..], 0).unwrap();
+
+        let oci = "application/vnd.oci.image.layer.v1.tar";
+        assert_eq!(decompress(Some(oci), b"layer"), b"layer");
+        assert_eq!(decompress(Some(&format!("{}+gzip", oci)), &gzip), b"layer");
+        assert_eq!(decompress(Some(&format!("{}+zstd", oci)), &zstd), b"layer");
+
+        let oci = "application/vnd.oci.image.layer.nondistributable.v1.tar";
+        assert_eq!(decompress(Some(&format!("{}+zstd", oci)), &zstd), b"layer");
+
+        let docker = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+        assert_eq!(decompress(Some(docker), &gzip), b"layer");
+        let foreign = "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
+        assert_eq!(decompress(Some(foreign), &gzip), b"layer");
+
+        assert!(layer(Some("application/x-bogus"))
+            .decompressor(&b""[..])
+            .is_err());
+    }
+
+    #[test]
+    fn sniff() {
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        gzip.write_all(b"layer").unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(b"layer").unwrap();
+        let xz = xz.finish().unwrap();
+
+        let mut bzip2 = bzip2::write::BzEncoder::new(Vec::new(), Default::default());
+        bzip2.write_all(b"layer").unwrap();
+        let bzip2 = bzip2.finish().unwrap();
+
+        let zstd = zstd::encode_all(&b"layer"[..], 0).unwrap();
+
+        // Without a media type
+        for data in [&gzip[..], &zstd, &xz, &bzip2, b"layer"] {
+            assert_eq!(decompress(None, data), b"layer");
+        }
+
+        // With a lying media type
+        let tar = "application/vnd.docker.image.rootfs.diff.tar";
+        assert_eq!(decompress(Some(tar), &gzip), b"layer");
+        let gz = "application/vnd.oci.image.layer.v1.tar+gzip";
+        assert_eq!(decompress(Some(gz), &zstd), b"layer");
+        assert_eq!(decompress(Some(gz), b"layer"), b"layer");
+
+        // Reads return a single byte at a time.
+        for data in [&gzip[..], &zstd, &xz, &bzip2, b"layer"] {
+            let mut out = Vec::new();
+            let reader = BufReader::with_capacity(1, data);
+            let mut reader = layer(None).decompressor(reader).unwrap();
+            reader.read_to_end(&mut out).unwrap();
+            assert_eq!(out, b"layer");
+        }
+    }
+    #[test]
+    fn tarball() {
+        let mut head = Header::new_gnu();
+        head.set_mode(0o644);
+        head.set_uid(0);
+        head.set_gid(0);
+        head.set_mtime(0);
+        head.set_size(5);
 
-        let rep = self.repo.get(&path, &[])?;
-        let len = rep
-            .header("Content-Length")
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(self.level.size);
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(&mut head, "BZh91AY", &b"layer"[..])
+            .unwrap();
+        let tar = builder.into_inner().unwrap();
 
-        let validator = Validator::new(rep.into_reader(), self.level.digest.clone());
-        Ok((len, validator))
+        // Tarballs start with the name of their first entry.
+        assert_eq!(decompress(None, &tar), tar);
     }
 }
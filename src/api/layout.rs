@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+//! Local image directories (format: OCI image layout)
+
+use super::blob::Body;
+use super::Source;
+use crate::formats::docker::v2::Layer;
+use crate::formats::oci::Index;
+use crate::formats::{Digest, Manifest};
+use crate::iotools::Validator;
+
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+
+/// An OCI image layout directory, as written by `skopeo copy ... oci:PATH`
+#[derive(Clone, Debug)]
+pub struct Layout(PathBuf);
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "oci:{}", self.0.display())
+    }
+}
+
+impl Layout {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self(dir.into())
+    }
+
+    /// Opens a blob (format: `blobs/ALGORITHM/HEX`)
+    fn file(&self, digest: &Digest) -> Result<(u64, File)> {
+        let digest = digest.to_string();
+        let (algorithm, hex) = digest.split_once(':').unwrap_or_default();
+        let path = self.0.join("blobs").join(algorithm).join(hex);
+
+        let file =
+            File::open(&path).with_context(|| format!("{} not found in {}", digest, self))?;
+        Ok((file.metadata()?.len(), file))
+    }
+
+    /// Reads a manifest blob, verifying it against its digest
+    fn read(&self, digest: &Digest, media_type: Option<&str>) -> Result<Manifest> {
+        let (.., file) = self.file(digest)?;
+        let mut body = Vec::new();
+        file.take(Manifest::MAX_SIZE).read_to_end(&mut body)?;
+
+        digest
+            .verify(&body)
+            .with_context(|| format!("invalid manifest in {}", self))?;
+
+        Manifest::parse(media_type, &body)
+            .map_err(|e| anyhow!("invalid manifest {} in {}: {}", digest, self, e))
+    }
+
+    /// Gets a manifest by digest or by reference
+    ///
+    /// References are matched against the `oci::REF_NAME` annotations in
+    /// `index.json`. An empty reference matches every image.
+    /// When several images match, they are returned as an index, so that the
+    /// one for the platform can be chosen.
+    fn find(&self, reference: &str, digest: Option<&Digest>) -> Result<Manifest> {
+        if let Some(digest) = digest {
+            return self.read(digest, None);
+        }
+
+        let path = self.0.join("index.json");
+        let index = std::fs::read(&path).with_context(|| format!("unable to read {:?}", path))?;
+        let mut index: Index = serde_json::from_slice(&index)
+            .with_context(|| format!("invalid image layout index: {:?}", path))?;
+
+        index
+            .manifests
+            .retain(|desc| reference.is_empty() || desc.name() == Some(reference));
+
+        match &index.manifests[..] {
+            [] => Err(anyhow!("no image named {:?} in {}", reference, self)),
+            [desc] => self.read(&desc.digest, Some(&desc.media_type)),
+            _ if index.manifests.iter().all(|desc| desc.platform.is_some()) => {
+                Ok(Manifest::OciIndex(index))
+            }
+            _ => Err(anyhow!("several images in {}; use oci:PATH:REF", self)),
+        }
+    }
+}
+
+impl Source for Layout {
+    fn manifest(&self, reference: &str, digest: Option<&Digest>) -> Result<(Manifest, bool)> {
+        Ok((self.find(reference, digest)?, false))
+    }
+
+    fn blob(&self, layer: &Layer) -> Result<(u64, Body)> {
+        let (len, file) = self.file(&layer.digest)?;
+        let body: Body = Box::new(file);
+        Ok((len, Box::new(Validator::new(body, layer.digest.clone()))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Layout;
+    use crate::formats::oci::{self, Descriptor, Index, REF_NAME};
+    use crate::formats::{Digest, Manifest};
+
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    /// Writes a blob, returning its digest
+    fn blob(dir: &Path, data: &[u8]) -> Digest {
+        let digest = Digest::sha256(data);
+        std::fs::create_dir_all(dir.join("blobs/sha256")).unwrap();
+        std::fs::write(
+            dir.join("blobs/sha256").join(&digest.to_string()[7..]),
+            data,
+        )
+        .unwrap();
+        digest
+    }
+
+    fn descriptor(media_type: &str, digest: Digest, size: u64) -> Descriptor {
+        Descriptor {
+            media_type: media_type.into(),
+            digest,
+            size,
+            urls: Vec::new(),
+            annotations: HashMap::new(),
+            platform: None,
+        }
+    }
+
+    /// Writes an image manifest with a single layer
+    fn image(dir: &Path, layer: Digest, size: u64) -> Digest {
+        let config = blob(dir, b"{}");
+        let manifest = oci::Manifest {
+            schema_version: 2,
+            media_type: Some(Manifest::OCI.into()),
+            config: descriptor("application/vnd.oci.image.config.v1+json", config, 2),
+            layers: vec![descriptor(
+                "application/vnd.oci.image.layer.v1.tar",
+                layer,
+                size,
+            )],
+            annotations: HashMap::new(),
+        };
+
+        blob(dir, &serde_json::to_vec(&manifest).unwrap())
+    }
+
+    #[test]
+    fn manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let empty = Digest::sha256(b"");
+        let amd64 = image(dir, empty.clone(), 0);
+        let arm64 = image(dir, Digest::sha256(b"{}"), 2);
+
+        // (format: name, platform, manifest)
+        let manifests = [
+            ("latest", "linux/amd64", &amd64),
+            ("latest", "linux/arm64", &arm64),
+            ("stable", "linux/amd64", &amd64),
+        ];
+
+        let index = Index {
+            schema_version: 2,
+            media_type: None,
+            manifests: manifests
+                .iter()
+                .map(|(name, platform, digest)| Descriptor {
+                    annotations: [(REF_NAME.into(), name.to_string())].into(),
+                    platform: Some(platform.parse().unwrap()),
+                    ..descriptor(Manifest::OCI, (*digest).clone(), 1)
+                })
+                .collect(),
+            annotations: HashMap::new(),
+        };
+        std::fs::write(dir.join("index.json"), serde_json::to_vec(&index).unwrap()).unwrap();
+
+        let layout = Layout::new(dir);
+        assert_eq!(layout.to_string(), format!("oci:{}", dir.display()));
+
+        match layout.find("stable", None).unwrap() {
+            Manifest::Oci(m) => assert_eq!(m.layers[0].digest, empty),
+            m => panic!("unexpected manifest: {:?}", m),
+        }
+
+        // Several images are offered for the platform to choose from.
+        match layout.find("latest", None).unwrap() {
+            Manifest::OciIndex(index) => assert_eq!(index.manifests.len(), 2),
+            m => panic!("unexpected manifest: {:?}", m),
+        }
+
+        assert!(layout.find("missing", None).is_err());
+        assert!(layout.find("", Some(&arm64)).is_ok());
+
+        // Blobs are verified against their digests.
+        std::fs::write(dir.join("blobs/sha256").join(&arm64.to_string()[7..]), "{}").unwrap();
+        assert!(layout.find("", Some(&arm64)).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+//! Registry mirrors (format: containers-registries.conf)
+
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+/// Which pulls a mirror may be used for
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Pull {
+    All,
+    DigestOnly,
+    TagOnly,
+}
+
+impl Pull {
+    /// Whether a pull by digest (or by tag) may use the mirror
+    pub fn allows(self, by_digest: bool) -> bool {
+        match self {
+            Self::All => true,
+            Self::DigestOnly => by_digest,
+            Self::TagOnly => !by_digest,
+        }
+    }
+}
+
+/// A mirror for all repositories under a prefix
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mirror {
+    /// The location replacing the prefix (format: host[:port][/path])
+    pub location: String,
+
+    /// Whether the mirror may be accessed over plain HTTP
+    pub insecure: bool,
+
+    /// Which pulls the mirror may be used for
+    pub pull: Pull,
+}
+
+impl FromStr for Mirror {
+    type Err = anyhow::Error;
+
+    /// Parses a mirror location (format: `host[:port][/path]`)
+    fn from_str(s: &str) -> Result<Self> {
+        let location = s.trim_end_matches('/');
+        if location.is_empty() || location.contains("://") {
+            return Err(anyhow!("invalid mirror location: {}", s));
+        }
+
+        Ok(Self {
+            location: location.into(),
+            insecure: false,
+            pull: Pull::All,
+        })
+    }
+}
+
+impl Mirror {
+    /// The host of the mirror
+    pub fn host(&self) -> &str {
+        self.location.split('/').next().unwrap_or_default()
+    }
+}
+
+/// Registry mirrors, by repository prefix
+#[derive(Clone, Debug, Default)]
+pub struct Mirrors(Vec<(String, Mirror)>);
+
+impl Mirrors {
+    /// Reads the mirrors from a `registries.conf` (version 2) file
+    pub fn read(&mut self, path: &Path) -> Result<()> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct Entry {
+            location: String,
+            #[serde(default)]
+            insecure: bool,
+            pull_from_mirror: Option<Pull>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct Registry {
+            prefix: Option<String>,
+            location: Option<String>,
+            #[serde(default)]
+            mirror_by_digest_only: bool,
+            #[serde(default)]
+            mirror: Vec<Entry>,
+        }
+
+        #[derive(Deserialize)]
+        struct File {
+            #[serde(default)]
+            registry: Vec<Registry>,
+        }
+
+        let file: File = toml::from_slice(&std::fs::read(path)?)
+            .with_context(|| format!("invalid registries file: {:?}", path))?;
+
+        for registry in file.registry {
+            let prefix = registry
+                .prefix
+                .or(registry.location)
+                .ok_or_else(|| anyhow!("registry without prefix or location in {:?}", path))?;
+
+            // The per-mirror setting overrides the per-registry one.
+            let default = match registry.mirror_by_digest_only {
+                true => Pull::DigestOnly,
+                false => Pull::All,
+            };
+
+            for entry in registry.mirror {
+                let mirror = Mirror {
+                    insecure: entry.insecure,
+                    pull: entry.pull_from_mirror.unwrap_or(default),
+                    ..entry.location.parse()?
+                };
+
+                self.insert(&prefix, mirror);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a mirror for a prefix, after any existing ones
+    pub fn insert(&mut self, prefix: &str, mirror: Mirror) {
+        let prefix = prefix.trim_end_matches('/');
+        self.0.push((prefix.into(), mirror));
+    }
+
+    /// Whether a prefix applies to a repository (format: host/path)
+    fn matches(prefix: &str, name: &str) -> bool {
+        match name.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    /// Gets the mirrored names of a repository (format: host/path), in order
+    ///
+    /// Like in `registries.conf`, only the longest matching prefix is used.
+    pub fn get(&self, name: &str) -> Vec<(String, &Mirror)> {
+        let longest = self
+            .0
+            .iter()
+            .filter(|(prefix, ..)| Self::matches(prefix, name))
+            .map(|(prefix, ..)| prefix.len())
+            .max();
+
+        self.0
+            .iter()
+            .filter(|(prefix, ..)| Some(prefix.len()) == longest && Self::matches(prefix, name))
+            .map(|(prefix, mirror)| {
+                let name = format!("{}{}", mirror.location, &name[prefix.len()..]);
+                (name, mirror)
+            })
+            .collect()
+    }
+
+    /// The hosts of mirrors that may be accessed over plain HTTP
+    pub fn insecure(&self) -> impl Iterator<Item = &str> {
+        self.0
+            .iter()
+            .filter(|(.., mirror)| mirror.insecure)
+            .map(|(.., mirror)| mirror.host())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Mirror, Mirrors, Pull};
+
+    use std::io::Write;
+
+    #[test]
+    fn read() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(
+            r#"
+            unqualified-search-registries = ["docker.io"]
+
+            [[registry]]
+            prefix = "docker.io"
+            location = "registry-1.docker.io"
+            mirror-by-digest-only = true
+
+            [[registry.mirror]]
+            location = "mirror.lan:5000/hub/"
+            insecure = true
+
+            [[registry.mirror]]
+            location = "backup.lan"
+            pull-from-mirror = "all"
+
+            [[registry]]
+            location = "docker.io/library/debian"
+
+            [[registry.mirror]]
+            location = "debian.lan/debian"
+
+            [[registry]]
+            location = "quay.io"
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let mut mirrors = Mirrors::default();
+        mirrors.insert("docker.io", "cmdline.lan".parse().unwrap());
+        mirrors.read(file.path()).unwrap();
+
+        let found = mirrors.get("docker.io/library/fedora");
+        let names: Vec<&str> = found.iter().map(|(name, ..)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "cmdline.lan/library/fedora",
+                "mirror.lan:5000/hub/library/fedora",
+                "backup.lan/library/fedora",
+            ]
+        );
+
+        let pulls: Vec<Pull> = found.iter().map(|(.., mirror)| mirror.pull).collect();
+        assert_eq!(pulls, [Pull::All, Pull::DigestOnly, Pull::All]);
+
+        // The longest prefix wins.
+        let found = mirrors.get("docker.io/library/debian");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, "debian.lan/debian");
+
+        assert_eq!(mirrors.get("docker.io/library/debianish").len(), 3);
+        assert!(mirrors.get("docker.iox/foo").is_empty());
+        assert!(mirrors.get("quay.io/coreos/etcd").is_empty());
+
+        let insecure: Vec<&str> = mirrors.insecure().collect();
+        assert_eq!(insecure, ["mirror.lan:5000"]);
+    }
+
+    #[test]
+    fn pull() {
+        assert!(Pull::All.allows(true) && Pull::All.allows(false));
+        assert!(Pull::DigestOnly.allows(true) && !Pull::DigestOnly.allows(false));
+        assert!(!Pull::TagOnly.allows(true) && Pull::TagOnly.allows(false));
+
+        assert!("".parse::<Mirror>().is_err());
+        assert!("https://mirror.lan".parse::<Mirror>().is_err());
+        assert_eq!(
+            "mirror.lan/hub".parse::<Mirror>().unwrap().host(),
+            "mirror.lan"
+        );
+    }
+}
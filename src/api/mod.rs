@@ -1,10 +1,27 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
+mod archive;
+mod auth;
+mod blob;
+mod cache;
+mod challenge;
+mod config;
 mod image;
 mod layer;
+mod layout;
+mod mirrors;
+mod registry;
 mod repository;
+mod source;
 
+pub use self::archive::Archive;
+pub use self::auth::{Auths, Credentials};
+pub use self::cache::{Blob, Cache, Policy};
+pub use self::config::{Config, Retry};
 pub use self::image::Image;
 pub use self::layer::Layer;
+pub use self::layout::Layout;
+pub use self::mirrors::{Mirror, Mirrors, Pull};
 pub use self::repository::Repository;
+pub use self::source::Source;
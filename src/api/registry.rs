@@ -0,0 +1,325 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+//! Images in registries, and in their mirrors and cache
+
+use super::blob::{self, Body, Open, Resume};
+use super::cache::Fill;
+use super::{Policy, Repository, Source};
+use crate::formats::docker::v2::Layer as Level;
+use crate::formats::{Digest, Manifest};
+use crate::iotools::{Either, Validator};
+
+use std::io::Read;
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use ureq::Response;
+
+impl Repository {
+    /// Reads a manifest from the cache, if any
+    fn cached_manifest(
+        &self,
+        reference: &str,
+        digest: Option<&Digest>,
+    ) -> Result<Option<Manifest>> {
+        let cache = match &self.config().cache {
+            Some(cache) => cache,
+            None => return Ok(None),
+        };
+
+        let name = format!("{}:{}", self, reference);
+        match cache.manifest(&name, digest)? {
+            Some(body) => Ok(Some(Manifest::parse(None, &body)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets a manifest, verifying it against the digest
+    ///
+    /// If no digest is given, the `Docker-Content-Digest` header is used
+    /// instead (when present).
+    fn pull(&self, reference: &str, digest: Option<&Digest>) -> Result<(Manifest, Vec<u8>)> {
+        let path = format!("manifests/{}", reference);
+        let accept = Manifest::MEDIA_TYPES.join(", ");
+        let rep = self.get(&path, &[("Accept", &accept)])?;
+
+        let kind = rep.header("Content-Type").map(|x| x.to_owned());
+        let header = rep.header("Docker-Content-Digest").map(|x| x.to_owned());
+        let mut body = Vec::new();
+        rep.into_reader()
+            .take(Manifest::MAX_SIZE)
+            .read_to_end(&mut body)?;
+
+        let manifest = Manifest::parse(kind.as_deref(), &body)
+            .map_err(|e| anyhow!("invalid manifest for {}:{}: {}", self, reference, e))?;
+
+        let expected = match (digest, header) {
+            (Some(digest), ..) => Some(digest.clone()),
+
+            // The digest of signed schema 1 manifests is calculated over the
+            // payload without the signatures, so we can't check it here.
+            (None, Some(..)) if matches!(manifest, Manifest::DockerV1(..)) => None,
+
+            (None, Some(header)) => Some(header.parse()?),
+            (None, None) => None,
+        };
+
+        if let Some(expected) = expected {
+            expected
+                .verify(&body)
+                .with_context(|| format!("invalid manifest for {}:{}", self, reference))?;
+        }
+
+        Ok((manifest, body))
+    }
+
+    /// Resolves a tag to a digest with a `HEAD` request, if the registry says
+    fn digest(&self, tag: &str) -> Result<Option<Digest>> {
+        let path = format!("manifests/{}", tag);
+        let accept = Manifest::MEDIA_TYPES.join(", ");
+        let rep = self.head(&path, &[("Accept", &accept)])?;
+
+        match rep.header("Docker-Content-Digest") {
+            Some(header) => Ok(Some(header.parse()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Pulls a manifest by tag, from a mirror if possible
+    ///
+    /// Mirrors may serve anything for a tag, so the tag is first resolved
+    /// to a digest upstream, which the mirrors must match. If the upstream
+    /// registry does not tell the digest, the mirrors are skipped.
+    fn pull_tag(&self, tag: &str) -> Result<(Manifest, Vec<u8>)> {
+        if !self.is_mirrored(false) {
+            return self.pull(tag, None);
+        }
+
+        let digest = self.digest(tag)?;
+        self.mirrored(false, |repo| {
+            // The upstream registry is tried last, and verifies itself.
+            if std::ptr::eq(repo, self) {
+                return repo.pull(tag, None);
+            }
+
+            match &digest {
+                Some(digest) => repo.pull(tag, Some(digest)),
+                None => Err(anyhow!("no digest for {}:{} upstream", self, tag)),
+            }
+        })
+    }
+
+    /// Connects to the first source of the blob that works
+    ///
+    /// The registry (or its mirrors) are tried first. Then, the URLs from
+    /// the descriptor are tried in order, as for foreign layers.
+    fn connect(&self, layer: &Level) -> Result<(String, Response, Open)> {
+        let path = format!("blobs/{}", layer.digest);
+
+        let error = match self.mirrored(true, |repo| Ok((repo.clone(), repo.get(&path, &[])?))) {
+            Ok((repo, rep)) => {
+                let name = format!("{}@{}", repo, layer.digest);
+                let open = move |offset| blob::get(&repo, &path, offset);
+                return Ok((name, rep, Box::new(open)));
+            }
+
+            Err(e) => e,
+        };
+
+        for url in &layer.urls {
+            match self.external(url, &[]) {
+                Ok(rep) => {
+                    let repo = self.clone();
+                    let name = url.clone();
+                    let url = url.clone();
+                    let open = move |offset| blob::external(&repo, &url, offset);
+                    return Ok((name, rep, Box::new(open)));
+                }
+
+                Err(e) => warn!("skipping {} for {}: {:#}", url, layer.digest, e),
+            }
+        }
+
+        Err(error)
+    }
+}
+
+impl Source for Repository {
+    /// Tags cannot contain a colon, so references that do are digests
+    fn resolve(&self, reference: &str) -> Result<Option<Digest>> {
+        match reference.contains(':') {
+            true => Ok(Some(reference.parse()?)),
+            false => Ok(None),
+        }
+    }
+
+    /// Pulls a manifest from the cache or the first mirror (or the
+    /// upstream) that works, following the cache policy
+    ///
+    /// Manifests pulled by digest never change, so the cache is always
+    /// tried first for them.
+    fn manifest(&self, reference: &str, digest: Option<&Digest>) -> Result<(Manifest, bool)> {
+        let config = self.config();
+        let first = digest.is_some() || config.policy != Policy::PreferNetwork;
+
+        if first {
+            if let Some(manifest) = self.cached_manifest(reference, digest)? {
+                return Ok((manifest, true));
+            }
+
+            if config.policy == Policy::CacheOnly {
+                return Err(anyhow!("{}:{} is not cached", self, reference));
+            }
+        }
+
+        let pulled = match digest {
+            Some(..) => self.mirrored(true, |repo| repo.pull(reference, digest)),
+            None => self.pull_tag(reference),
+        };
+
+        let error = match pulled {
+            Ok((manifest, body)) => {
+                if let Some(cache) = &config.cache {
+                    // Tags are named after the upstream repository, not the mirror.
+                    let name = format!("{}:{}", self, reference);
+                    let name = Some(name).filter(|_| digest.is_none());
+                    let digest = digest.cloned().unwrap_or_else(|| Digest::sha256(&body));
+
+                    if let Err(e) =
+                        cache.store(name.as_deref(), manifest.media_type(), &digest, &body)
+                    {
+                        warn!("unable to cache {}:{}: {:#}", self, reference, e);
+                    }
+                }
+
+                return Ok((manifest, false));
+            }
+
+            Err(e) => e,
+        };
+
+        if !first {
+            if let Some(manifest) = self.cached_manifest(reference, digest)? {
+                warn!("using cached {}:{}: {:#}", self, reference, error);
+                return Ok((manifest, true));
+            }
+        }
+
+        Err(error)
+    }
+
+    fn cached(&self, layer: &Level) -> bool {
+        let cache = self.config().cache.as_ref();
+        cache.is_some_and(|cache| cache.contains(&layer.digest))
+    }
+
+    /// Downloads the blob from the cache or the registry
+    fn blob(&self, layer: &Level) -> Result<(u64, Body)> {
+        let digest = &layer.digest;
+        let cache = self.config().cache.as_ref();
+
+        // Damaged blobs are missing from the cache, and downloaded again.
+        if let Some((len, file)) = cache.map(|c| c.open(digest)).transpose()?.flatten() {
+            return Ok((len, Box::new(file)));
+        }
+
+        if self.config().policy == Policy::CacheOnly {
+            return Err(anyhow!("{} is not cached", digest));
+        }
+
+        let (name, rep, open) = self.connect(layer)?;
+        let len = rep
+            .header("Content-Length")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(layer.size);
+
+        // Interrupted downloads continue from the same source.
+        let size = Some(layer.size).filter(|size| *size > 0);
+        let retry = self.config().retry;
+        let deadline = self.config().deadline;
+        let first = Box::new(rep.into_reader());
+        let reader = Resume::new(name, first, size, retry, deadline, open);
+
+        let reader: Body = Box::new(reader);
+        let entry = cache.map(|c| c.entry(digest)).transpose();
+        let reader = match entry {
+            Ok(Some(entry)) => Either::Two(Fill::new(reader, entry, digest.clone())),
+            Ok(None) => Either::One(Validator::new(reader, digest.clone())),
+            Err(e) => {
+                warn!("not caching {}: {:#}", digest, e);
+                Either::One(Validator::new(reader, digest.clone()))
+            }
+        };
+
+        Ok((len, Box::new(reader)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::{Config, Repository, Source};
+    use crate::fixtures::registry;
+    use crate::formats::oci::{self, Descriptor};
+    use crate::formats::{Digest, Manifest};
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    const PATH: &str = "/v2/library/debian/manifests/latest";
+
+    /// An image manifest with a single (uncompressed) layer
+    fn manifest(layer: &[u8]) -> String {
+        let descriptor = |media_type: &str, data: &[u8]| Descriptor {
+            media_type: media_type.into(),
+            digest: Digest::sha256(data),
+            size: data.len() as u64,
+            urls: Vec::new(),
+            annotations: HashMap::new(),
+            platform: None,
+        };
+
+        serde_json::to_string(&oci::Manifest {
+            schema_version: 2,
+            media_type: Some(Manifest::OCI.into()),
+            config: descriptor("application/vnd.oci.image.config.v1+json", b"{}"),
+            layers: vec![descriptor("application/vnd.oci.image.layer.v1.tar", layer)],
+            annotations: HashMap::new(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn mirrored_tag() {
+        let upstream = manifest(b"upstream");
+        let forged = manifest(b"forged");
+        let (host, log) = registry([(PATH.into(), upstream.clone())].into());
+
+        let pull = |mirror: String| {
+            let mut config = Config::default();
+            config.mirrors.insert(&host, mirror.parse().unwrap());
+            let name = format!("{}/library/debian", host);
+            let (repo, tag) = Repository::new(&name, Arc::new(config)).unwrap();
+            match repo.manifest(tag, None).unwrap() {
+                (Manifest::Oci(m), false) => m.layers[0].digest.clone(),
+                m => panic!("unexpected manifest: {:?}", m),
+            }
+        };
+
+        // A mirror serving another manifest for the tag is skipped.
+        let (mirror, mirrored) = registry([(PATH.into(), forged)].into());
+        assert_eq!(pull(mirror), Digest::sha256(b"upstream"));
+        assert_eq!(*mirrored.lock().unwrap(), [format!("GET {}", PATH)]);
+        assert_eq!(
+            *log.lock().unwrap(),
+            [format!("HEAD {}", PATH), format!("GET {}", PATH)]
+        );
+
+        // Otherwise, only the digest is asked from upstream.
+        log.lock().unwrap().clear();
+        let (mirror, mirrored) = registry([(PATH.into(), upstream)].into());
+        assert_eq!(pull(mirror), Digest::sha256(b"upstream"));
+        assert_eq!(*mirrored.lock().unwrap(), [format!("GET {}", PATH)]);
+        assert_eq!(*log.lock().unwrap(), [format!("HEAD {}", PATH)]);
+    }
+}
@@ -1,21 +1,55 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
-use super::Image;
+use super::challenge::Challenge;
+use super::{Config, Pull};
+use crate::formats::Digest;
 
-use std::cmp::max;
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::Result;
-use regex::Regex;
+use anyhow::{anyhow, Result};
+use log::warn;
 use serde::Deserialize;
-use ureq::Response;
+use ureq::{Agent, ErrorKind, Request, Response};
+use url::Url;
+
+/// A cached `Authorization` header value
+#[derive(Clone, Debug)]
+struct Token {
+    header: String,
+    expires: Option<Instant>,
+}
+
+impl Token {
+    fn valid(&self) -> bool {
+        self.expires.is_none_or(|expires| Instant::now() < expires)
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Repository {
     host: String,
     path: String,
+    config: Arc<Config>,
+
+    /// The agent is shared between clones (i.e. layer downloads)
+    agent: Agent,
+
+    /// Agents for other hosts (i.e. token realms), shared between clones
+    agents: Arc<Mutex<HashMap<String, Agent>>>,
+
+    /// Tokens by scope, shared between clones
+    tokens: Arc<Mutex<HashMap<String, Token>>>,
+
+    /// Whether an insecure host fell back to plain HTTP
+    plain: Arc<AtomicBool>,
+
+    /// Mirrors of this repository, in the order they are tried
+    mirrors: Vec<(Pull, Repository)>,
 }
 
 impl Display for Repository {
@@ -33,53 +67,410 @@ impl Display for Repository {
 }
 
 impl Repository {
-    fn auth(&self, wwwauth: &str) -> Result<String> {
+    /// The default lifetime of a token, from the token authentication spec
+    const TOKEN_LIFETIME: u64 = 60;
+
+    /// How long before their expiry tokens are no longer used
+    const TOKEN_MARGIN: u64 = 10;
+
+    /// Gets a cached, unexpired token for the scope
+    fn token(&self, scope: &str) -> Option<String> {
+        let tokens = self.tokens.lock().unwrap();
+        tokens
+            .get(scope)
+            .filter(|token| token.valid())
+            .map(|token| token.header.clone())
+    }
+
+    /// Caches a token for the scope
+    fn store(&self, scope: &str, header: &str, expires_in: Option<u64>) {
+        let expires = expires_in.map(|secs| {
+            let secs = secs.saturating_sub(Self::TOKEN_MARGIN);
+            Instant::now() + Duration::from_secs(secs)
+        });
+
+        let token = Token {
+            header: header.into(),
+            expires,
+        };
+
+        self.tokens.lock().unwrap().insert(scope.into(), token);
+    }
+
+    /// Gets the agent for the host of a URL
+    ///
+    /// Other hosts (i.e. token realms or CDNs) get their own certificates
+    /// and proxy.
+    fn agent(&self, url: &str) -> Result<Agent> {
+        let host = Config::authority(url).ok_or_else(|| anyhow!("invalid URL: {}", url))?;
+        if host == self.host {
+            return Ok(self.agent.clone());
+        }
+
+        let mut agents = self.agents.lock().unwrap();
+        if let Some(agent) = agents.get(host) {
+            return Ok(agent.clone());
+        }
+
+        let agent = self.config.agent(host)?;
+        agents.insert(host.into(), agent.clone());
+        Ok(agent)
+    }
+
+    /// The scope needed to pull from this repository
+    fn scope(&self) -> String {
+        format!("repository:{}:pull", self.path)
+    }
+
+    /// Answers an authentication challenge, returning the `Authorization`
+    ///
+    /// A cached token is reused unless it was just rejected.
+    fn auth(&self, challenges: &[Challenge], rejected: Option<&str>) -> Result<String> {
         #[derive(Deserialize)]
         struct Auth {
-            token: String,
+            token: Option<String>,
+            access_token: Option<String>,
+            expires_in: Option<u64>,
+        }
+
+        let credentials = self.config.auths.get(&self.host);
+
+        let bearer = challenges.iter().find(|c| c.scheme == "bearer");
+        let basic = challenges.iter().find(|c| c.scheme == "basic");
+        let challenge = match (bearer, basic) {
+            (Some(bearer), ..) => bearer,
+
+            // Basic-only registries take the credentials directly.
+            (None, Some(..)) => {
+                let header = credentials
+                    .map(|c| c.basic())
+                    .ok_or_else(|| anyhow!("no credentials for {}", self.host))?;
+                self.store("", &header, None);
+                return Ok(header);
+            }
+
+            (None, None) => {
+                let schemes: Vec<&str> = challenges.iter().map(|c| c.scheme.as_str()).collect();
+                return Err(anyhow!(
+                    "unsupported authentication schemes for {}: {}",
+                    self.host,
+                    schemes.join(", ")
+                ));
+            }
+        };
+
+        let scope = challenge.param("scope").unwrap_or_default();
+        if let Some(header) = self.token(scope) {
+            if Some(&*header) != rejected {
+                return Ok(header);
+            }
+        }
+
+        let realm = challenge
+            .param("realm")
+            .ok_or_else(|| anyhow!("no realm in authentication challenge from {}", self.host))?;
+
+        let mut url = Url::parse(realm)?;
+        for (k, v) in &challenge.params {
+            if k != "realm" && k != "error" {
+                url.query_pairs_mut().append_pair(k, v);
+            }
+        }
+
+        let basic = credentials.map(|c| c.basic());
+        let auth: Auth = self
+            .call("GET", url.as_str(), &[], basic.as_deref())?
+            .into_json()?;
+        let token = auth
+            .token
+            .or(auth.access_token)
+            .ok_or_else(|| anyhow!("no token from {}", realm))?;
+
+        let header = format!("Bearer {}", token);
+        let expires_in = auth.expires_in.unwrap_or(Self::TOKEN_LIFETIME);
+        self.store(scope, &header, Some(expires_in));
+        Ok(header)
+    }
+
+    /// Limits a request to the time left before the deadline
+    fn limit(&self, req: Request) -> Request {
+        match self.config.deadline {
+            Some(deadline) => req.timeout(deadline.saturating_duration_since(Instant::now())),
+            None => req,
+        }
+    }
+
+    /// The maximum number of redirects to follow (i.e. to a CDN)
+    const MAX_REDIRECTS: u32 = 8;
+
+    /// Whether a redirect may keep the `Authorization` header
+    ///
+    /// Like `ureq`, only on the same host and without downgrading to HTTP.
+    fn same_host(from: &Url, into: &Url) -> bool {
+        from.host() == into.host()
+            && from.port_or_known_default() == into.port_or_known_default()
+            && (from.scheme() == into.scheme() || into.scheme() == "https")
+    }
+
+    /// Requests a URL, following redirects with the agent for each host
+    ///
+    /// Each host is checked against `no_proxy` separately. Redirects only
+    /// keep the `Authorization` header on the same host, so that registry
+    /// credentials are never sent to a CDN.
+    fn call(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        auth: Option<&str>,
+    ) -> Result<Response> {
+        let mut url = Url::parse(url)?;
+        let mut auth = auth;
+
+        for _ in 0..=Self::MAX_REDIRECTS {
+            let agent = self.agent(url.as_str())?;
+            let mut req = self.limit(agent.request(method, url.as_str()));
+            for (k, v) in headers {
+                req = req.set(k, v);
+            }
+
+            if let Some(auth) = auth {
+                req = req.set("Authorization", auth);
+            }
+
+            let rep = req.call()?;
+            let location = match rep.status() {
+                301 | 302 | 303 | 307 | 308 => rep.header("Location"),
+                _ => None,
+            };
+
+            let next = match location {
+                Some(location) => url.join(location)?,
+                None => return Ok(rep),
+            };
+
+            if !Self::same_host(&url, &next) {
+                auth = None;
+            }
+
+            url = next;
         }
 
-        const RE: &str = "([a-z]+)=\"([^\"]+)\"";
+        Err(anyhow!("too many redirects for {}", self))
+    }
+
+    /// Sends a request, falling back to plain HTTP for insecure hosts
+    ///
+    /// Unlike with `ureq`, error statuses are returned as responses.
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        auth: Option<&str>,
+    ) -> Result<Response> {
+        let plain = self.plain.load(Ordering::Relaxed);
+        let scheme = match plain {
+            true => "http",
+            false => "https",
+        };
+
+        let url = format!("{}://{}/v2/{}/{}", scheme, self.host, self.path, path);
+
+        match self
+            .call(method, &url, headers, auth)
+            .map_err(|e| e.downcast())
+        {
+            Ok(rep) | Err(Ok(ureq::Error::Status(.., rep))) => Ok(rep),
 
-        let mut map = HashMap::new();
-        let re = Regex::new(RE).unwrap();
-        for find in re.find_iter(wwwauth) {
-            for caps in re.captures_iter(find.as_str()) {
-                let k = caps.get(1).unwrap().as_str();
-                let v = caps.get(2).unwrap().as_str();
-                map.insert(k, v);
+            Err(Ok(ureq::Error::Transport(e))) if !plain && self.config.insecure(&self.host) => {
+                warn!("falling back to plain HTTP for {}: {}", self.host, e);
+                self.plain.store(true, Ordering::Relaxed);
+                self.send(method, path, headers, auth)
             }
+
+            Err(Ok(e)) => Err(e.into()),
+            Err(Err(e)) => Err(e),
         }
+    }
+
+    /// Statuses that may go away when retrying
+    const TRANSIENT: &'static [u16] = &[429, 500, 502, 503, 504];
 
-        let base = map.remove("realm").unwrap();
-        let join: Vec<String> = map.iter().map(|(k, v)| [*k, *v].join("=")).collect();
-        let args = join.join("&");
-        let url = format!("{}?{}", base, args);
+    /// Parses a `Retry-After` header (in seconds or as an HTTP date)
+    fn retry_after(header: Option<&str>) -> Option<Duration> {
+        let header = header?.trim();
 
-        let auth: Auth = ureq::get(&url).call()?.into_json()?;
-        let token = format!("Bearer {}", auth.token);
-        Ok(token)
+        match header.parse() {
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(..) => httpdate::parse_http_date(header)
+                .ok()
+                .map(|date| date.duration_since(SystemTime::now()).unwrap_or_default()),
+        }
     }
 
+    /// Gets a resource, retrying transient failures
     pub(super) fn get(&self, path: &str, headers: &[(&str, &str)]) -> Result<Response> {
-        let url = format!("https://{}/v2/{}/{}", self.host, self.path, path);
+        self.retrying("GET", path, headers)
+    }
+
+    /// Gets the headers of a resource, retrying transient failures
+    pub(super) fn head(&self, path: &str, headers: &[(&str, &str)]) -> Result<Response> {
+        self.retrying("HEAD", path, headers)
+    }
+
+    /// Requests a resource, retrying transient failures
+    ///
+    /// Connection errors and transient statuses are retried with backoff,
+    /// unless the server asks for a specific delay with `Retry-After`.
+    fn retrying(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> Result<Response> {
+        let retry = self.config.retry;
+        let mut attempt = 0;
 
-        let mut auth = false;
-        let mut req = ureq::get(&url);
-        for (k, v) in headers {
-            req = req.set(k, v);
-            auth |= *k == "Authorization";
+        loop {
+            let (error, after) = match self.fetch(method, path, headers) {
+                Ok(rep) => match rep.status() {
+                    200..=299 => return Ok(rep),
+                    status => {
+                        let error = anyhow!("{}: status code {}", Self::redact(&rep), status);
+                        match Self::TRANSIENT.contains(&status) {
+                            true => (error, Self::retry_after(rep.header("Retry-After"))),
+                            false => return Err(error),
+                        }
+                    }
+                },
+
+                // The token realm may fail too.
+                Err(e) => match e.downcast_ref::<ureq::Error>() {
+                    Some(ureq::Error::Status(status, rep)) if Self::TRANSIENT.contains(status) => {
+                        let after = Self::retry_after(rep.header("Retry-After"));
+                        (e, after)
+                    }
+
+                    Some(ureq::Error::Transport(t))
+                        if matches!(
+                            t.kind(),
+                            ErrorKind::Dns
+                                | ErrorKind::ConnectionFailed
+                                | ErrorKind::Io
+                                | ErrorKind::ProxyConnect
+                        ) =>
+                    {
+                        (e, None)
+                    }
+
+                    _ => return Err(e),
+                },
+            };
+
+            if attempt >= retry.attempts {
+                return Err(error);
+            }
+
+            let delay = after.unwrap_or_else(|| retry.delay(attempt));
+            attempt += 1;
+
+            if let Some(deadline) = self.config.deadline {
+                if Instant::now() + delay >= deadline {
+                    return Err(error.context(format!("deadline exceeded for {}", self)));
+                }
+            }
+
+            warn!("retrying {} in {:?}: {:#}", self, delay, error);
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// Gets a resource once, without retrying
+    ///
+    /// This is for callers that retry on their own (i.e. resumed downloads).
+    pub(super) fn once(&self, path: &str, headers: &[(&str, &str)]) -> Result<Response> {
+        let rep = self.fetch("GET", path, headers)?;
+        match rep.status() {
+            200..=299 => Ok(rep),
+            status => Err(anyhow!("{}: status code {}", Self::redact(&rep), status)),
         }
+    }
 
-        match req.call() {
-            Err(ureq::Error::Status(401, rep)) if !auth && rep.has("Www-Authenticate") => {
-                let token = self.auth(rep.header("Www-Authenticate").unwrap())?;
-                self.get(path, &[("Authorization", &token)])
+    /// Requests a resource, answering any authentication challenge
+    ///
+    /// Like with `send()`, error statuses are returned as responses.
+    fn fetch(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> Result<Response> {
+        // Proactively attach any token we already have.
+        let cached = self.token(&self.scope()).or_else(|| self.token(""));
+        let mut rep = self.send(method, path, headers, cached.as_deref())?;
+
+        if rep.status() == 401 && rep.has("Www-Authenticate") {
+            let mut challenges = Vec::new();
+            for header in rep.all("Www-Authenticate") {
+                challenges.extend(Challenge::parse(header)?);
             }
 
+            let token = self.auth(&challenges, cached.as_deref())?;
+            rep = self.send(method, path, headers, Some(&token))?;
+        }
+
+        Ok(rep)
+    }
+
+    /// The final URL of a response, without any query
+    ///
+    /// Redirects to a CDN often carry signatures in the query.
+    fn redact(rep: &Response) -> &str {
+        let url = rep.get_url();
+        url.split('?').next().unwrap_or(url)
+    }
+
+    /// Gets a resource outside of the registry API (i.e. a foreign layer)
+    ///
+    /// No credentials are sent, since the resource may be on any host.
+    pub(super) fn external(&self, url: &str, headers: &[(&str, &str)]) -> Result<Response> {
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            return Err(anyhow!("unsupported URL: {}", url));
+        }
+
+        match self
+            .call("GET", url, headers, None)
+            .map_err(|e| e.downcast())
+        {
             Ok(rep) => Ok(rep),
-            Err(e) => Err(e.into()),
+            Err(Ok(ureq::Error::Status(status, rep))) => {
+                Err(anyhow!("{}: status code {}", Self::redact(&rep), status))
+            }
+            Err(Ok(e)) => Err(e.into()),
+            Err(Err(e)) => Err(e),
+        }
+    }
+
+    /// Tries each applicable mirror in order, then the repository itself
+    ///
+    /// Callers must verify the content against its digest, so that a mirror
+    /// cannot serve anything but the upstream content.
+    pub(super) fn mirrored<T>(
+        &self,
+        by_digest: bool,
+        mut func: impl FnMut(&Self) -> Result<T>,
+    ) -> Result<T> {
+        for (pull, mirror) in &self.mirrors {
+            if pull.allows(by_digest) {
+                match func(mirror) {
+                    Ok(x) => return Ok(x),
+                    Err(e) => warn!("skipping mirror {} of {}: {:#}", mirror, self, e),
+                }
+            }
         }
+
+        func(self)
+    }
+
+    /// Whether any mirror may be tried for a pull by digest (or by tag)
+    pub(super) fn is_mirrored(&self, by_digest: bool) -> bool {
+        self.mirrors.iter().any(|(pull, ..)| pull.allows(by_digest))
+    }
+
+    /// The configuration for accessing the repository
+    pub(super) fn config(&self) -> &Config {
+        &self.config
     }
 
     const DEFAULT_REGISTRY: &'static str = "docker.io";
@@ -90,18 +481,28 @@ impl Repository {
     const ALIASES: &'static [(&'static str, &'static str)] =
         &[("docker.io", "registry.hub.docker.com")];
 
-    pub fn new(mut repository: &str) -> Result<(Self, &str)> {
-        // Remove any tag or digest
+    pub fn new(mut repository: &str, config: Arc<Config>) -> Result<(Self, &str)> {
+        // Remove any digest. Digests contain a colon, so do this first.
+        let mut digest = None;
+        if let Some((lhs, rhs)) = repository.split_once('@') {
+            rhs.parse::<Digest>()?;
+            repository = lhs;
+            digest = Some(rhs);
+        }
+
+        // Remove any tag
         let sep = repository.rfind('/').unwrap_or_default();
         let lbl = repository.rfind(':').unwrap_or_default();
-        let dig = repository.rfind('@').unwrap_or_default();
         let mut tag = Self::DEFAULT_TAG;
-        if lbl > sep || dig > sep {
-            let (lhs, rhs) = repository.split_at(max(lbl, dig));
+        if lbl > sep {
+            let (lhs, rhs) = repository.split_at(lbl);
             repository = lhs;
             tag = &rhs[1..];
         }
 
+        // When both are given, the digest wins.
+        let tag = digest.unwrap_or(tag);
+
         // Extract the registry
         let mut host = Self::DEFAULT_REGISTRY;
         if let Some((lhs, rhs)) = repository.find('/').map(|n| repository.split_at(n)) {
@@ -125,15 +526,237 @@ impl Repository {
             }
         }
 
-        let out = Self {
+        let mut out = Self::at(host, path, config.clone())?;
+
+        let name = out.to_string();
+        for (name, mirror) in config.mirrors.get(&name) {
+            let (host, path) = name
+                .split_once('/')
+                .ok_or_else(|| anyhow!("invalid mirror: {}", mirror.location))?;
+            out.mirrors
+                .push((mirror.pull, Self::at(host, path.into(), config.clone())?));
+        }
+
+        Ok((out, tag))
+    }
+
+    /// Creates a repository without any mirrors
+    fn at(host: &str, path: String, config: Arc<Config>) -> Result<Self> {
+        Ok(Self {
             host: host.into(),
             path,
-        };
+            agent: config.agent(host)?,
+            agents: Default::default(),
+            config,
+            tokens: Default::default(),
+            plain: Default::default(),
+            mirrors: Vec::new(),
+        })
+    }
+}
 
-        Ok((out, tag))
+#[cfg(test)]
+mod test {
+    use super::{Config, Repository};
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    const DIGEST: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn new() {
+        let (repo, tag) = Repository::new("debian", Default::default()).unwrap();
+        assert_eq!(repo.to_string(), "docker.io/library/debian");
+        assert_eq!(tag, "latest");
+
+        let (repo, tag) =
+            Repository::new("localhost:5000/foo/bar:baz", Default::default()).unwrap();
+        assert_eq!(repo.to_string(), "localhost:5000/foo/bar");
+        assert_eq!(tag, "baz");
+
+        let name = format!("registry.gitlab.com/wyrcan/debian@{}", DIGEST);
+        let (repo, tag) = Repository::new(&name, Default::default()).unwrap();
+        assert_eq!(repo.to_string(), "registry.gitlab.com/wyrcan/debian");
+        assert_eq!(tag, DIGEST);
+
+        let name = format!("localhost:5000/wyrcan/debian:bookworm@{}", DIGEST);
+        let (repo, tag) = Repository::new(&name, Default::default()).unwrap();
+        assert_eq!(repo.to_string(), "localhost:5000/wyrcan/debian");
+        assert_eq!(tag, DIGEST);
+
+        assert!(Repository::new("debian@sha256:1234", Default::default()).is_err());
+    }
+
+    #[test]
+    fn mirrors() {
+        let mut config = Config::default();
+        config
+            .mirrors
+            .insert("docker.io", "mirror.lan:5000".parse().unwrap());
+        config
+            .mirrors
+            .insert("docker.io/library", "hub.lan/lib".parse().unwrap());
+        let config = Arc::new(config);
+
+        let (repo, ..) =
+            Repository::new("registry.hub.docker.com/library/debian", config.clone()).unwrap();
+        let names: Vec<String> = repo.mirrors.iter().map(|(.., m)| m.to_string()).collect();
+        assert_eq!(names, ["hub.lan/lib/debian"]);
+
+        let (repo, ..) = Repository::new("wyrcan/debian", config.clone()).unwrap();
+        let names: Vec<String> = repo.mirrors.iter().map(|(.., m)| m.to_string()).collect();
+        assert_eq!(names, ["mirror.lan:5000/wyrcan/debian"]);
+
+        // Mirrors are tried in order, falling back to the upstream.
+        let mut tried = Vec::new();
+        let out = repo.mirrored(true, |r| {
+            tried.push(r.to_string());
+            match r.host == "mirror.lan:5000" {
+                true => Err(anyhow::anyhow!("offline")),
+                false => Ok(r.host.clone()),
+            }
+        });
+        assert_eq!(out.unwrap(), "registry.hub.docker.com");
+        assert_eq!(
+            tried,
+            ["mirror.lan:5000/wyrcan/debian", "docker.io/wyrcan/debian"]
+        );
+
+        let (repo, ..) = Repository::new("quay.io/coreos/etcd", config).unwrap();
+        assert!(repo.mirrors.is_empty());
     }
 
-    pub fn image(&self, tag: &str) -> Result<Image> {
-        Image::new(self.clone(), tag)
+    #[test]
+    fn external() {
+        let (repo, ..) = Repository::new("debian", Default::default()).unwrap();
+        assert!(repo.external("ftp://example.com/layer", &[]).is_err());
+        assert!(repo.external("/layer", &[]).is_err());
+    }
+
+    #[test]
+    fn retry_after() {
+        assert_eq!(Repository::retry_after(None), None);
+        assert_eq!(
+            Repository::retry_after(Some(" 120 ")),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            Repository::retry_after(Some("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::ZERO)
+        );
+
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        let after = Repository::retry_after(Some(&httpdate::fmt_http_date(later))).unwrap();
+        assert!(after > Duration::from_secs(3590) && after <= Duration::from_secs(3600));
+
+        assert_eq!(Repository::retry_after(Some("soon")), None);
+    }
+
+    #[test]
+    fn tokens() {
+        let (repo, ..) = Repository::new("debian", Default::default()).unwrap();
+        let scope = repo.scope();
+        assert_eq!(scope, "repository:library/debian:pull");
+        assert_eq!(repo.token(&scope), None);
+
+        // Tokens are shared between clones.
+        repo.clone().store(&scope, "Bearer abc", Some(300));
+        assert_eq!(repo.token(&scope).as_deref(), Some("Bearer abc"));
+        assert_eq!(repo.token(""), None);
+
+        // Tokens are not used near their expiry.
+        repo.store(&scope, "Bearer def", Some(Repository::TOKEN_MARGIN));
+        assert_eq!(repo.token(&scope), None);
+
+        repo.store("", "Basic xyz", None);
+        assert_eq!(repo.token("").as_deref(), Some("Basic xyz"));
+    }
+
+    #[test]
+    fn agents() {
+        let (repo, ..) = Repository::new("debian", Default::default()).unwrap();
+        let realm = "https://auth.docker.io/token";
+        assert!(repo.agent("https://registry.hub.docker.com/v2/").is_ok());
+        assert!(repo.agents.lock().unwrap().is_empty());
+
+        // Token realms get their own agent, shared between clones.
+        assert!(repo.clone().agent(realm).is_ok());
+        let hosts: Vec<String> = repo.agents.lock().unwrap().keys().cloned().collect();
+        assert_eq!(hosts, ["auth.docker.io"]);
+
+        assert!(repo.agent("/token").is_err());
+    }
+
+    #[test]
+    fn redirects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in [
+                format!("302 Found\r\nLocation: http://localhost:{}/cdn", port),
+                "200 OK\r\nContent-Length: 2".into(),
+            ] {
+                let (mut stream, ..) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0];
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+
+                let response = format!("HTTP/1.1 {}\r\nConnection: close\r\n\r\nok", response);
+                stream.write_all(response.as_bytes()).unwrap();
+                requests.push(String::from_utf8(request).unwrap().to_lowercase());
+            }
+
+            requests
+        });
+
+        let (repo, ..) = Repository::new("debian", Default::default()).unwrap();
+        let url = format!("http://127.0.0.1:{}/v2/", port);
+        let rep = repo.call("GET", &url, &[], Some("Bearer abc")).unwrap();
+        assert_eq!(rep.into_string().unwrap(), "ok");
+
+        // The other host gets its own agent, and no credentials.
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("authorization: bearer abc"));
+        assert!(requests[1].starts_with("get /cdn "));
+        assert!(!requests[1].contains("authorization"));
+
+        let mut hosts: Vec<String> = repo.agents.lock().unwrap().keys().cloned().collect();
+        hosts.sort();
+        assert_eq!(
+            hosts,
+            [format!("127.0.0.1:{}", port), format!("localhost:{}", port)]
+        );
+    }
+
+    #[test]
+    fn same_host() {
+        let url = |url: &str| url.parse().unwrap();
+        let from = url("https://quay.io/v2/");
+        assert!(Repository::same_host(
+            &from,
+            &url("https://quay.io:443/v2/blob")
+        ));
+        assert!(!Repository::same_host(
+            &from,
+            &url("https://cdn.quay.io/blob")
+        ));
+        assert!(!Repository::same_host(&from, &url("http://quay.io/v2/")));
+        assert!(!Repository::same_host(
+            &from,
+            &url("https://quay.io:8443/v2/")
+        ));
+
+        let from = url("http://localhost:5000/v2/");
+        assert!(Repository::same_host(
+            &from,
+            &url("http://localhost:5000/blob")
+        ));
     }
 }
@@ -0,0 +1,219 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use super::blob::Body;
+use super::{Archive, Config, Layout, Repository};
+use crate::formats::docker::v2::Layer as Level;
+use crate::formats::{Digest, Manifest};
+
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+/// Where images are read from (like a registry, a directory or a tarball)
+pub trait Source: Debug + Display + Send + Sync {
+    /// Finds the digest in a reference, if any
+    ///
+    /// By default, references are digests when they parse as such, so
+    /// that names may contain colons.
+    fn resolve(&self, reference: &str) -> Result<Option<Digest>> {
+        Ok(reference.parse().ok())
+    }
+
+    /// Gets a manifest by reference (and by digest, when known)
+    ///
+    /// Returns whether the manifest was read from a cache.
+    fn manifest(&self, reference: &str, digest: Option<&Digest>) -> Result<(Manifest, bool)>;
+
+    /// Whether the blob can be read without the network
+    fn cached(&self, _layer: &Level) -> bool {
+        false
+    }
+
+    /// Opens a blob, which fails at the end unless it matches its digest
+    fn blob(&self, layer: &Level) -> Result<(u64, Body)>;
+}
+
+/// Parses an image name into its source and reference (see `Image::open`)
+pub(super) fn parse(name: &str, config: Arc<Config>) -> Result<(Arc<dyn Source>, &str)> {
+    // Like skopeo, the path ends at the first colon.
+    if let Some(rest) = name.strip_prefix("oci:") {
+        let (path, reference) = rest.split_once(':').unwrap_or((rest, ""));
+        return Ok((Arc::new(Layout::new(path)), reference));
+    }
+
+    if let Some(rest) = name.strip_prefix("docker-archive:") {
+        let (path, reference) = rest.split_once(':').unwrap_or((rest, ""));
+        return Ok((Arc::new(Archive::open(path)?), reference));
+    }
+
+    let (repo, tag) = Repository::new(name, config)?;
+    Ok((Arc::new(repo), tag))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, Body, Level, Source};
+    use crate::api::Image;
+    use crate::formats::docker::v2::Platform;
+    use crate::formats::oci::{self, Descriptor, Index};
+    use crate::formats::{Digest, Manifest};
+    use crate::iotools::Validator;
+
+    use std::collections::HashMap;
+    use std::fmt::Display;
+    use std::io::Read;
+    use std::sync::Arc;
+
+    use anyhow::{anyhow, Result};
+
+    /// An in-memory source (format: reference or digest => data)
+    #[derive(Debug, Default)]
+    struct Memory(HashMap<String, Vec<u8>>);
+
+    impl Display for Memory {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "memory")
+        }
+    }
+
+    impl Memory {
+        /// Adds a blob, returning its digest
+        fn add(&mut self, data: impl Into<Vec<u8>>) -> Digest {
+            let data = data.into();
+            let digest = Digest::sha256(&data);
+            self.0.insert(digest.to_string(), data);
+            digest
+        }
+
+        fn get(&self, key: &str) -> Result<&[u8]> {
+            let data = self
+                .0
+                .get(key)
+                .ok_or_else(|| anyhow!("{} not found", key))?;
+            Ok(data)
+        }
+    }
+
+    impl Source for Memory {
+        fn manifest(&self, reference: &str, digest: Option<&Digest>) -> Result<(Manifest, bool)> {
+            let data = self.get(reference)?;
+            if let Some(digest) = digest {
+                digest.verify(data)?;
+            }
+
+            Ok((Manifest::parse(None, data)?, false))
+        }
+
+        fn blob(&self, layer: &Level) -> Result<(u64, Body)> {
+            let data = self.get(&layer.digest.to_string())?.to_vec();
+            let body: Body = Box::new(std::io::Cursor::new(data));
+            let len = layer.size;
+            Ok((len, Box::new(Validator::new(body, layer.digest.clone()))))
+        }
+    }
+
+    fn descriptor(media_type: &str, digest: Digest, size: u64) -> Descriptor {
+        Descriptor {
+            media_type: media_type.into(),
+            digest,
+            size,
+            urls: Vec::new(),
+            annotations: HashMap::new(),
+            platform: None,
+        }
+    }
+
+    /// An image manifest with a single (uncompressed) layer
+    fn manifest(layer: Digest, size: u64) -> Vec<u8> {
+        let config = Digest::sha256(b"{}");
+        serde_json::to_vec(&oci::Manifest {
+            schema_version: 2,
+            media_type: Some(Manifest::OCI.into()),
+            config: descriptor("application/vnd.oci.image.config.v1+json", config, 2),
+            layers: vec![descriptor(
+                "application/vnd.oci.image.layer.v1.tar",
+                layer,
+                size,
+            )],
+            annotations: HashMap::new(),
+        })
+        .unwrap()
+    }
+
+    /// An image index of manifests, by platform
+    fn index(manifests: &[(&str, Digest)]) -> Vec<u8> {
+        let manifests = manifests
+            .iter()
+            .map(|(platform, digest)| Descriptor {
+                platform: Some(platform.parse().unwrap()),
+                ..descriptor(Manifest::OCI, digest.clone(), 1)
+            })
+            .collect();
+
+        serde_json::to_vec(&Index {
+            schema_version: 2,
+            media_type: Some(Manifest::OCI_INDEX.into()),
+            manifests,
+            annotations: HashMap::new(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_names() {
+        let (source, tag) = parse("oci:/tmp/image:v1", Default::default()).unwrap();
+        assert_eq!((source.to_string().as_str(), tag), ("oci:/tmp/image", "v1"));
+
+        let (source, tag) = parse("oci:image", Default::default()).unwrap();
+        assert_eq!((source.to_string().as_str(), tag), ("oci:image", ""));
+
+        let (source, tag) = parse("debian:oci", Default::default()).unwrap();
+        assert_eq!(
+            (source.to_string().as_str(), tag),
+            ("docker.io/library/debian", "oci")
+        );
+
+        // Only registries reject references that look like bad digests.
+        assert!(source.resolve("sha256:bogus").is_err());
+        assert!(source.resolve("oci").unwrap().is_none());
+        let layout = parse("oci:image", Default::default()).unwrap().0;
+        assert!(layout.resolve("sha256:bogus").unwrap().is_none());
+    }
+
+    #[test]
+    fn image_layers() {
+        let mut memory = Memory::default();
+        let amd64 = memory.add(*b"amd64");
+        let arm64 = memory.add(*b"arm64");
+        memory.0.insert(amd64.to_string(), b"bogus".to_vec());
+        let amd64 = memory.add(manifest(amd64, 5));
+        let arm64 = memory.add(manifest(arm64, 5));
+        let index = index(&[("linux/amd64", amd64), ("linux/arm64", arm64)]);
+        memory.0.insert("latest".into(), index);
+
+        let source: Arc<dyn Source> = Arc::new(memory);
+        let platform: Platform = "linux/arm64".parse().unwrap();
+        let image = Image::new(source.clone(), "latest", &platform).unwrap();
+        assert_eq!(image.to_string(), "memory:latest");
+        assert!(!image.cached());
+
+        let layers = image.layers().unwrap();
+        assert_eq!(layers.len(), 1);
+        assert!(!layers[0].cached());
+
+        let (len, mut reader) = layers[0].download().unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!((len, &data[..]), (5, &b"arm64"[..]));
+
+        // Blobs are verified against their digests.
+        let platform: Platform = "linux/amd64".parse().unwrap();
+        let image = Image::new(source.clone(), "latest", &platform).unwrap();
+        let (.., mut reader) = image.layers().unwrap()[0].download().unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+
+        assert!(Image::new(source, "missing", &platform).is_err());
+    }
+}
@@ -0,0 +1,511 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+//! Parsing of the kernel command line
+//!
+//! Tokenization follows `next_arg()` in the kernel's `lib/cmdline.c` so
+//! that we see exactly the same parameters as the kernel (and init) do.
+
+use crate::api::{Credentials, Mirror, Policy};
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+
+/// A single kernel parameter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Param {
+    pub key: String,
+    pub val: Option<String>,
+}
+
+impl Display for Param {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Values with whitespace must be quoted. However, the kernel has no
+        // escaping, so values with unbalanced inner quotes cannot roundtrip.
+        let quote = |s: &str| s.bytes().any(Cmdline::space) || s.starts_with('"');
+
+        match &self.val {
+            None if quote(&self.key) => write!(f, "\"{}\"", self.key),
+            None => write!(f, "{}", self.key),
+            Some(val) if quote(val) => write!(f, "{}=\"{}\"", self.key, val),
+            Some(val) => write!(f, "{}={}", self.key, val),
+        }
+    }
+}
+
+/// What to do with the configuration stored in EFI NVRAM
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Efi {
+    Write,
+    Clear,
+}
+
+impl FromStr for Efi {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "write" => Ok(Self::Write),
+            "clear" => Ok(Self::Clear),
+            _ => Err(anyhow!("invalid value for wyr.efi: {}", s)),
+        }
+    }
+}
+
+/// The wyrcan options found on the command line
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    /// The container image to boot (`wyr.img`)
+    pub img: Option<String>,
+
+    /// Arguments for the next kernel, in order (`wyr.arg`)
+    pub args: Vec<String>,
+
+    /// Network configuration, without the `wyr.net.` prefix (`wyr.net.*`)
+    pub net: Vec<(String, String)>,
+
+    /// What to do with the EFI configuration (`wyr.efi`)
+    pub efi: Option<Efi>,
+
+    /// Registry credentials, by host (`wyr.auth=USER:PASS@HOST`)
+    pub auth: Vec<(String, Credentials)>,
+
+    /// Hosts that may be accessed over plain HTTP (`wyr.insecure`)
+    pub insecure: Vec<String>,
+
+    /// Extra CA certificates to trust (`wyr.ca`)
+    pub ca: Vec<PathBuf>,
+
+    /// Directories with per-host certificates (`wyr.certs`)
+    pub certs: Vec<PathBuf>,
+
+    /// Registry mirrors, by repository prefix (`wyr.mirror=PREFIX=LOCATION`)
+    pub mirror: Vec<(String, Mirror)>,
+
+    /// The proxy for registry traffic (`wyr.proxy`)
+    pub proxy: Option<String>,
+
+    /// Hosts or domains that bypass the proxy (`wyr.noproxy`)
+    pub noproxy: Vec<String>,
+
+    /// How often to retry failed requests and downloads (`wyr.retries`)
+    pub retries: Option<u32>,
+
+    /// The delay before the first retry (`wyr.backoff`, in seconds)
+    pub backoff: Option<Duration>,
+
+    /// How long to wait for a connection or data (`wyr.timeout`, in seconds)
+    pub timeout: Option<Duration>,
+
+    /// How long all registry requests may take (`wyr.deadline`, in seconds)
+    pub deadline: Option<Duration>,
+
+    /// The label of the cache partition (`wyr.cache`)
+    pub cache: Option<String>,
+
+    /// When to use the cache instead of the registry (`wyr.cachepolicy`)
+    pub cachepolicy: Option<Policy>,
+}
+
+/// The parsed kernel command line
+///
+/// Parameters after a `--` separator are passed by the kernel to init.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cmdline {
+    params: Vec<Param>,
+    init: Option<Vec<Param>>,
+}
+
+impl FromStr for Cmdline {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::parse(s.as_bytes()))
+    }
+}
+
+impl Display for Cmdline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut sep = "";
+
+        for param in &self.params {
+            write!(f, "{}{}", sep, param)?;
+            sep = " ";
+        }
+
+        if let Some(init) = &self.init {
+            write!(f, "{}--", sep)?;
+            for param in init {
+                write!(f, " {}", param)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Cmdline {
+    /// Option prefixes, with the legacy prefix last
+    const PREFIXES: &'static [&'static str] = &["wyr.", "wyrcan."];
+
+    /// Whitespace as defined by the kernel's `isspace()`
+    ///
+    /// The kernel's ctype table is Latin-1, so this includes NBSP (0xa0).
+    fn space(b: u8) -> bool {
+        matches!(b, b' ' | b'\t' | b'\n' | 0x0b | 0x0c | b'\r' | 0xa0)
+    }
+
+    fn skip(args: &[u8]) -> &[u8] {
+        let n = args.iter().take_while(|b| Self::space(**b)).count();
+        &args[n..]
+    }
+
+    /// Splits off the next parameter, like the kernel's `next_arg()`
+    fn next(mut args: &[u8]) -> (Param, &[u8]) {
+        let quoted = args.first() == Some(&b'"');
+        if quoted {
+            args = &args[1..];
+        }
+
+        // Find the end of the parameter and the first `=`. Just like the
+        // kernel, an `=` at index zero is not treated as a separator.
+        let mut quote = quoted;
+        let mut equals = 0;
+        let mut i = 0;
+        while i < args.len() {
+            if Self::space(args[i]) && !quote {
+                break;
+            }
+
+            if equals == 0 && args[i] == b'=' {
+                equals = i;
+            }
+
+            if args[i] == b'"' {
+                quote = !quote;
+            }
+
+            i += 1;
+        }
+
+        // Strip the quotes around the value.
+        let mut end = i;
+        let mut val = None;
+        if equals != 0 {
+            let mut start = equals + 1;
+            if start < i && args[start] == b'"' {
+                start += 1;
+                if args[i - 1] == b'"' {
+                    end = i - 1;
+                }
+            }
+
+            val = Some(&args[start.min(end)..end]);
+        }
+
+        // Strip the quotes around the whole parameter.
+        if quoted && end == i && i > 0 && args[i - 1] == b'"' {
+            end = i - 1;
+            val = val.map(|v| &v[..v.len() - 1]);
+        }
+
+        let key = match equals {
+            0 => &args[..end],
+            n => &args[..n],
+        };
+
+        let param = Param {
+            key: String::from_utf8_lossy(key).into(),
+            val: val.map(|v| String::from_utf8_lossy(v).into()),
+        };
+
+        (param, &args[(i + 1).min(args.len())..])
+    }
+
+    fn parse(args: &[u8]) -> Self {
+        let mut cmdline = Self::default();
+
+        let mut args = Self::skip(args);
+        while !args.is_empty() {
+            let (param, rest) = Self::next(args);
+            args = Self::skip(rest);
+
+            match &mut cmdline.init {
+                Some(init) => init.push(param),
+                None if param.val.is_none() && param.key == "--" => cmdline.init = Some(vec![]),
+                None => cmdline.params.push(param),
+            }
+        }
+
+        cmdline
+    }
+
+    /// Reads the command line from a file (usually `/proc/cmdline`)
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::parse(&std::fs::read(path)?))
+    }
+
+    /// Adds a parameter for the kernel
+    pub fn push(&mut self, param: Param) {
+        self.params.push(param);
+    }
+
+    /// Appends the kernel and init parameters of another command line
+    pub fn append(&mut self, other: Self) {
+        self.params.extend(other.params);
+
+        if let Some(init) = other.init {
+            self.init.get_or_insert_with(Vec::new).extend(init);
+        }
+    }
+
+    /// Parses the wyrcan options
+    ///
+    /// Later values override earlier ones, except for repeatable options.
+    pub fn options(&self) -> Result<Options> {
+        let mut options = Options::default();
+
+        for param in &self.params {
+            let name = match Self::PREFIXES
+                .iter()
+                .find_map(|p| param.key.strip_prefix(p))
+            {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let val = || {
+                param
+                    .val
+                    .clone()
+                    .ok_or_else(|| anyhow!("missing value: {}", param.key))
+            };
+
+            let secs = || {
+                let val = val()?;
+                val.parse()
+                    .ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .ok_or_else(|| anyhow!("invalid value for {}: {}", param.key, val))
+            };
+
+            match name {
+                "img" => options.img = Some(val()?),
+                "arg" => options.args.push(val()?),
+                "efi" => options.efi = Some(val()?.parse()?),
+                "auth" => {
+                    // Passwords may contain `@`, but hosts may not.
+                    let val = val()?;
+                    let (credentials, host) = val
+                        .rsplit_once('@')
+                        .ok_or_else(|| anyhow!("invalid wyr.auth (format: USER:PASS@HOST)"))?;
+                    options.auth.push((host.into(), credentials.parse()?));
+                }
+                "insecure" => options.insecure.push(val()?),
+                "ca" => options.ca.push(val()?.into()),
+                "certs" => options.certs.push(val()?.into()),
+                "mirror" => {
+                    let val = val()?;
+                    let (prefix, location) = val
+                        .split_once('=')
+                        .ok_or_else(|| anyhow!("invalid wyr.mirror (format: PREFIX=LOCATION)"))?;
+                    options.mirror.push((prefix.into(), location.parse()?));
+                }
+                "proxy" => options.proxy = Some(val()?),
+                "noproxy" => options.noproxy.push(val()?),
+                "retries" => {
+                    let val = val()?;
+                    let retries = val
+                        .parse()
+                        .map_err(|_| anyhow!("invalid value for wyr.retries: {}", val))?;
+                    options.retries = Some(retries);
+                }
+                "backoff" => options.backoff = Some(secs()?),
+                "timeout" => options.timeout = Some(secs()?),
+                "deadline" => options.deadline = Some(secs()?),
+                "cache" => options.cache = Some(val()?),
+                "cachepolicy" => options.cachepolicy = Some(val()?.parse()?),
+                _ => match name.strip_prefix("net.") {
+                    Some(net) => options.net.push((net.into(), val()?)),
+                    None => warn!("ignoring unknown option: {}", param.key),
+                },
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cmdline, Efi, Param};
+
+    fn param(key: &str, val: Option<&str>) -> Param {
+        Param {
+            key: key.into(),
+            val: val.map(Into::into),
+        }
+    }
+
+    fn params(cmdline: &str) -> Vec<Param> {
+        let cmdline: Cmdline = cmdline.parse().unwrap();
+        cmdline.params
+    }
+
+    #[test]
+    fn tokenize() {
+        assert_eq!(params(""), []);
+        assert_eq!(params(" \t\n"), []);
+        assert_eq!(
+            params("  quiet root=/dev/sda1\tro \n"),
+            [
+                param("quiet", None),
+                param("root", Some("/dev/sda1")),
+                param("ro", None)
+            ]
+        );
+
+        // Quoted values
+        assert_eq!(params("a=\"b c\""), [param("a", Some("b c"))]);
+        assert_eq!(params("a=\"\""), [param("a", Some(""))]);
+        assert_eq!(params("a=\""), [param("a", Some(""))]);
+        assert_eq!(params("a="), [param("a", Some(""))]);
+
+        // Inner quotes are kept.
+        assert_eq!(params("a=b\"c d\""), [param("a", Some("b\"c d\""))]);
+        assert_eq!(params("a\"b c\"=d"), [param("a\"b c\"", Some("d"))]);
+
+        // Leading quotes cover the whole parameter.
+        assert_eq!(
+            params("\"a=b c\" d"),
+            [param("a", Some("b c")), param("d", None)]
+        );
+        assert_eq!(params("\"a b\""), [param("a b", None)]);
+        assert_eq!(
+            params("\"a=\"b c\"\""),
+            [param("a", Some("b")), param("c\"\"", None)]
+        );
+
+        // An `=` at index zero is not a separator.
+        assert_eq!(params("=a"), [param("=a", None)]);
+        assert_eq!(params("=a=b"), [param("=a", Some("b"))]);
+
+        // Unterminated quotes run to the end.
+        assert_eq!(params("a=\"b c d"), [param("a", Some("b c d"))]);
+
+        // The kernel treats NBSP as whitespace.
+        assert_eq!(
+            Cmdline::parse(b"a\xa0b").params,
+            [param("a", None), param("b", None)]
+        );
+    }
+
+    #[test]
+    fn init() {
+        let cmdline: Cmdline = "quiet -- single x=\"y z\"".parse().unwrap();
+        assert_eq!(cmdline.params, [param("quiet", None)]);
+        assert_eq!(
+            cmdline.init.unwrap(),
+            [param("single", None), param("x", Some("y z"))]
+        );
+
+        // Only `--` without a value is a separator.
+        let cmdline: Cmdline = "--=x -- a".parse().unwrap();
+        assert_eq!(cmdline.params, [param("--", Some("x"))]);
+        assert_eq!(cmdline.init.unwrap(), [param("a", None)]);
+    }
+
+    #[test]
+    fn serialize() {
+        for cmdline in [
+            "",
+            "quiet",
+            "a=b c d=\"e f\" g=",
+            "\"a b\" c -- d e=\"f g\"",
+            "-- init",
+            "a=b\"c\"",
+        ] {
+            let parsed: Cmdline = cmdline.parse().unwrap();
+            assert_eq!(parsed.to_string(), cmdline);
+        }
+
+        let mut cmdline: Cmdline = "console=ttyS0 -- single".parse().unwrap();
+        cmdline.append("quiet log-buf-len=1M -- emergency".parse().unwrap());
+        cmdline.push(param("wyr.img", Some("debian")));
+        assert_eq!(
+            cmdline.to_string(),
+            "console=ttyS0 quiet log-buf-len=1M wyr.img=debian -- single emergency"
+        );
+    }
+
+    #[test]
+    fn options() {
+        let cmdline: Cmdline = "quiet wyr.img=debian wyr.arg=\"quiet log-buf-len=1M\" \
+            root=/dev/sda wyr.img=fedora wyr.arg=ro wyr.net.eth0.Match.Name=eth0 \
+            wyrcan.efi=clear wyr.auth=me:p@ss@registry.example.com wyr.insecure=localhost:5000 \
+            wyr.ca=/etc/ca.pem wyr.certs=/etc/certs.d wyr.mirror=docker.io=mirror.lan/hub \
+            wyr.proxy=proxy.lan:3128 wyr.noproxy=.lan wyr.retries=10 wyr.backoff=0.5 \
+            wyr.timeout=30 wyr.deadline=600 wyr.cache=WYRCACHE wyr.cachepolicy=prefer-cache \
+            -- wyr.img=ignored"
+            .parse()
+            .unwrap();
+
+        let options = cmdline.options().unwrap();
+        assert_eq!(options.img.as_deref(), Some("fedora"));
+        assert_eq!(options.args, ["quiet log-buf-len=1M", "ro"]);
+        assert_eq!(
+            options.net,
+            [("eth0.Match.Name".to_string(), "eth0".to_string())]
+        );
+        assert_eq!(options.efi, Some(Efi::Clear));
+        assert_eq!(
+            options.auth,
+            [("registry.example.com".into(), "me:p@ss".parse().unwrap())]
+        );
+        assert_eq!(options.insecure, ["localhost:5000"]);
+        assert_eq!(options.ca, [std::path::PathBuf::from("/etc/ca.pem")]);
+        assert_eq!(options.certs, [std::path::PathBuf::from("/etc/certs.d")]);
+        assert_eq!(
+            options.mirror,
+            [("docker.io".into(), "mirror.lan/hub".parse().unwrap())]
+        );
+        assert_eq!(options.proxy.as_deref(), Some("proxy.lan:3128"));
+        assert_eq!(options.noproxy, [".lan"]);
+        assert_eq!(options.retries, Some(10));
+        assert_eq!(options.backoff, Some(std::time::Duration::from_millis(500)));
+        assert_eq!(options.timeout, Some(std::time::Duration::from_secs(30)));
+        assert_eq!(options.deadline, Some(std::time::Duration::from_secs(600)));
+        assert_eq!(options.cache.as_deref(), Some("WYRCACHE"));
+        assert_eq!(options.cachepolicy, Some(crate::api::Policy::PreferCache));
+
+        // Non-wyrcan parameters keep their order.
+        let keys: Vec<&str> = cmdline.params.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(keys[0], "quiet");
+        assert_eq!(keys[3], "root");
+
+        for invalid in [
+            "wyr.img",
+            "wyr.efi=bogus",
+            "wyr.net.eth0.Match.Name",
+            "wyr.auth=me:pass",
+            "wyr.auth=me@registry.example.com",
+            "wyr.mirror=mirror.lan",
+            "wyr.mirror=docker.io=https://mirror.lan",
+            "wyr.retries=-1",
+            "wyr.backoff=-1",
+            "wyr.backoff=forever",
+            "wyr.timeout",
+            "wyr.deadline=NaN",
+            "wyr.cache",
+            "wyr.cachepolicy=never",
+        ] {
+            let cmdline: Cmdline = invalid.parse().unwrap();
+            assert!(cmdline.options().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,358 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use super::efi::Variable;
+use super::initrd::{Extent, Initrd};
+use super::registry::Registry;
+use super::unpacker::Unpacker;
+use super::Command;
+use crate::api::{Cache, Config, Image, Policy};
+use crate::cmdline::Cmdline;
+use crate::formats::docker::v2::Platform;
+use crate::iotools::memfd;
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Error, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use log::{info, warn};
+
+/// Boots a container using kexec
+#[derive(Parser, Debug)]
+pub struct Boot {
+    /// The kernel cmdline to read the configuration from
+    #[clap(long, default_value = "/proc/cmdline")]
+    cmdline: PathBuf,
+
+    /// The efivarfs mount point, used when the cmdline has no configuration
+    #[clap(long, default_value = "/sys/firmware/efi/efivars")]
+    efivarfs: PathBuf,
+
+    /// Write the kernel, initrd and cmdline into this directory instead
+    #[clap(long)]
+    dry_run: Option<PathBuf>,
+
+    /// Don't display the progress bar
+    #[clap(short, long)]
+    quiet: bool,
+
+    #[clap(flatten)]
+    registry: Registry,
+}
+
+impl Boot {
+    const KERNEL: &'static str = "boot/wyrcan.kernel";
+    const CMDLINE: &'static str = "boot/wyrcan.cmdline";
+
+    /// Where the cache partition (`wyr.cache`) is mounted
+    const CACHE: &'static str = "/run/wyrcan/cache";
+
+    /// How long refreshing the cache may take at most
+    const REFRESH: Duration = Duration::from_secs(300);
+
+    /// Mounts the filesystem with the given label
+    ///
+    /// Like `mount(8)`, this tries each filesystem type the kernel supports.
+    fn mount(label: &str, target: &Path) -> Result<()> {
+        let source = Path::new("/dev/disk/by-label").join(label);
+        let source = CString::new(source.as_os_str().as_bytes())?;
+        std::fs::create_dir_all(target)?;
+        let target = CString::new(target.as_os_str().as_bytes())?;
+
+        let mut error = anyhow!("no filesystem types");
+        let kinds = std::fs::read_to_string("/proc/filesystems")?;
+        for kind in kinds.lines().filter(|l| !l.starts_with("nodev")) {
+            let kind = CString::new(kind.trim())?;
+            let ret = unsafe {
+                libc::mount(
+                    source.as_ptr(),
+                    target.as_ptr(),
+                    kind.as_ptr(),
+                    0,
+                    std::ptr::null(),
+                )
+            };
+
+            if ret == 0 {
+                return Ok(());
+            }
+
+            error = Error::last_os_error().into();
+        }
+
+        Err(error).with_context(|| format!("unable to mount the cache partition {}", label))
+    }
+
+    /// Flushes and unmounts the cache partition
+    ///
+    /// systemd-kexec.service unmounts it anyway, but only after we exit, when
+    /// nobody reports a failure to flush the cache.
+    fn unmount(target: &Path) -> Result<()> {
+        let dir = File::open(target)?;
+        if unsafe { libc::syncfs(dir.as_raw_fd()) } < 0 {
+            return Err(Error::last_os_error()).context("unable to sync the cache partition");
+        }
+        drop(dir);
+
+        let target = CString::new(target.as_os_str().as_bytes())?;
+        if unsafe { libc::umount(target.as_ptr()) } < 0 {
+            return Err(Error::last_os_error()).context("unable to unmount the cache partition");
+        }
+
+        Ok(())
+    }
+
+    /// Refreshes the cache from the registry in the background
+    ///
+    /// The latest manifest and any missing layers are downloaded, which
+    /// stores them in the cache. All requests give up at the deadline, so
+    /// joining the refresh takes at most that long.
+    fn refresh(name: String, config: &Config, deadline: Instant) -> JoinHandle<Result<()>> {
+        let config = Arc::new(Config {
+            policy: Policy::PreferNetwork,
+            deadline: Some(deadline),
+            ..config.clone()
+        });
+
+        spawn(move || {
+            let image = Image::open(&name, config, &Platform::host())?;
+            if image.cached() {
+                return Err(anyhow!("unable to reach the registry"));
+            }
+
+            for layer in image.layers()? {
+                if !layer.cached() {
+                    let (.., mut reader) = layer.download()?;
+                    std::io::copy(&mut reader, &mut std::io::sink())?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn extract(file: &mut File, extent: Extent, into: &mut File) -> Result<()> {
+        file.seek(SeekFrom::Start(extent.offset))?;
+        std::io::copy(&mut file.take(extent.size), into)?;
+        into.rewind()?;
+        Ok(())
+    }
+
+    fn load(kernel: &File, initrd: &File, cmdline: &str) -> Result<()> {
+        let cmdline = CString::new(cmdline)?;
+        let cmdline = cmdline.as_bytes_with_nul();
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_kexec_file_load,
+                kernel.as_raw_fd(),
+                initrd.as_raw_fd(),
+                cmdline.len(),
+                cmdline.as_ptr(),
+                0,
+            )
+        };
+
+        if ret < 0 {
+            return Err(Error::last_os_error()).context("unable to load the kernel");
+        }
+
+        Ok(())
+    }
+}
+
+impl Command for Boot {
+    fn execute(self) -> Result<()> {
+        let mut options = Cmdline::read(&self.cmdline)?.options()?;
+
+        // Fall back to the configuration persisted by `wyrcan efi`, which
+        // also configures the registry and the cache.
+        if options.img.is_none() {
+            if let Some(saved) = Variable::new(&self.efivarfs).read()? {
+                options = saved.parse::<Cmdline>()?.options()?;
+            }
+        }
+
+        let mut config = self.registry.config(&options)?;
+
+        // Without the cache partition, we can still try the registry.
+        let mut mounted = false;
+        if let Some(label) = &options.cache {
+            match Self::mount(label, Path::new(Self::CACHE)) {
+                Ok(()) => {
+                    Arc::make_mut(&mut config).cache = Some(Cache::new(Self::CACHE));
+                    mounted = true;
+                }
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+
+        let name = options
+            .img
+            .ok_or_else(|| anyhow!("no container image specified (wyr.img)"))?;
+
+        let image = Image::open(&name, config.clone(), &Platform::host())?;
+
+        // Preferring the cache may have used stale tags. (Otherwise, the
+        // cache was only used because the registry just failed.)
+        let deadline = Instant::now() + Self::REFRESH;
+        let refresh = match image.cached() && config.policy == Policy::PreferCache {
+            true => Some(Self::refresh(name.clone(), &config, deadline)),
+            false => None,
+        };
+        let unpacker = Unpacker::new(&image, !self.quiet)?;
+
+        // Convert the container into an initrd
+        let file = memfd("initrd")?;
+        let mut initrd = Initrd::new(file.try_clone()?, Some(file));
+        for mut bundle in unpacker.bundles()? {
+            for entry in bundle.entries()? {
+                initrd.append(&mut entry?)?;
+            }
+        }
+
+        let kernel = initrd
+            .resolve(Self::KERNEL)
+            .ok_or_else(|| anyhow!("{} not found in {}", Self::KERNEL, image))?;
+        let extra = initrd.resolve(Self::CMDLINE);
+        let mut initrd = initrd.finish()?;
+
+        // Extract the kernel
+        let mut kfile = memfd("kernel")?;
+        Self::extract(&mut initrd, kernel, &mut kfile)?;
+
+        // Assemble the cmdline from the container and our arguments
+        let mut cmdline = Cmdline::default();
+        if let Some(extent) = extra {
+            let mut extra = String::new();
+            initrd.seek(SeekFrom::Start(extent.offset))?;
+            (&mut initrd).take(extent.size).read_to_string(&mut extra)?;
+            cmdline.append(extra.parse()?);
+        }
+        for arg in &options.args {
+            cmdline.append(arg.parse()?);
+        }
+        let cmdline = cmdline.to_string();
+        initrd.rewind()?;
+
+        match self.dry_run {
+            Some(dir) => {
+                std::fs::create_dir_all(&dir)?;
+                std::io::copy(&mut kfile, &mut File::create(dir.join("kernel"))?)?;
+                std::io::copy(&mut initrd, &mut File::create(dir.join("initrd"))?)?;
+                std::fs::write(dir.join("cmdline"), cmdline)?;
+            }
+
+            None => Self::load(&kfile, &initrd, &cmdline)?,
+        }
+
+        // The refresh must be done with the cache before it is unmounted.
+        if let Some(refresh) = refresh {
+            match refresh.join() {
+                Ok(Ok(())) => info!("refreshed the cache for {}", name),
+                Ok(Err(e)) => warn!("unable to refresh the cache for {}: {:#}", name, e),
+                Err(..) => warn!("unable to refresh the cache for {}", name),
+            }
+        }
+
+        if mounted {
+            if let Err(e) = Self::unmount(Path::new(Self::CACHE)) {
+                warn!("{:#}", e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Boot;
+    use crate::api::{Cache, Config, Policy};
+    use crate::fixtures::registry;
+    use crate::formats::oci::{self, Descriptor, Index, REF_NAME};
+    use crate::formats::{Digest, Manifest};
+
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    fn descriptor(media_type: &str, data: &[u8]) -> Descriptor {
+        Descriptor {
+            media_type: media_type.into(),
+            digest: Digest::sha256(data),
+            size: data.len() as u64,
+            urls: Vec::new(),
+            annotations: HashMap::new(),
+            platform: None,
+        }
+    }
+
+    /// An image manifest with a single (uncompressed) layer
+    fn manifest(layer: &[u8]) -> String {
+        serde_json::to_string(&oci::Manifest {
+            schema_version: 2,
+            media_type: Some(Manifest::OCI.into()),
+            config: descriptor("application/vnd.oci.image.config.v1+json", b"{}"),
+            layers: vec![descriptor("application/vnd.oci.image.layer.v1.tar", layer)],
+            annotations: HashMap::new(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn refresh() {
+        let (stale, fresh) = (manifest(b"stale"), manifest(b"fresh"));
+        let layer = format!("/v2/library/debian/blobs/{}", Digest::sha256(b"fresh"));
+        let (host, ..) = registry(
+            [
+                ("/v2/library/debian/manifests/latest".into(), fresh.clone()),
+                (layer, "fresh".into()),
+            ]
+            .into(),
+        );
+
+        // The cache has an older image for the tag.
+        let dir = tempfile::tempdir().unwrap();
+        let name = format!("{}/library/debian", host);
+        let index = Index {
+            schema_version: 2,
+            media_type: None,
+            manifests: vec![Descriptor {
+                annotations: [(REF_NAME.into(), format!("{}:latest", name))].into(),
+                ..descriptor(Manifest::OCI, stale.as_bytes())
+            }],
+            annotations: HashMap::new(),
+        };
+        let index = serde_json::to_vec(&index).unwrap();
+        std::fs::write(dir.path().join("index.json"), index).unwrap();
+
+        let config = Config {
+            cache: Some(Cache::new(dir.path())),
+            policy: Policy::PreferCache,
+            ..Default::default()
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+        Boot::refresh(name.clone(), &config, deadline)
+            .join()
+            .unwrap()
+            .unwrap();
+
+        // The tag now names the latest image, whose layer is cached.
+        let index = std::fs::read(dir.path().join("index.json")).unwrap();
+        let index: Index = serde_json::from_slice(&index).unwrap();
+        let tag = format!("{}:latest", name);
+        let found = index.manifests.iter().find(|d| d.name() == Some(&tag));
+        assert_eq!(found.unwrap().digest, Digest::sha256(fresh.as_bytes()));
+        let cache = Cache::new(dir.path());
+        assert!(cache.open(&Digest::sha256(b"fresh")).unwrap().is_some());
+    }
+}
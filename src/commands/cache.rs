@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use super::Command;
+use crate::api::{Blob, Cache as Store};
+
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+
+/// Manages a blob cache directory (see `--cache-dir`)
+#[derive(Subcommand, Debug)]
+pub enum Cache {
+    Ls(Ls),
+    Prune(Prune),
+}
+
+impl Command for Cache {
+    fn execute(self) -> Result<()> {
+        match self {
+            Self::Ls(cmd) => cmd.execute(),
+            Self::Prune(cmd) => cmd.execute(),
+        }
+    }
+}
+
+/// Prints a blob (format: digest size age)
+fn print(blob: &Blob) {
+    let age = SystemTime::now()
+        .duration_since(blob.used)
+        .unwrap_or_default();
+
+    println!("{} {} {}s", blob.digest, blob.size, age.as_secs());
+}
+
+/// Lists the cached blobs, from least to most recently used
+#[derive(Parser, Debug)]
+pub struct Ls {
+    /// The cache directory
+    dir: PathBuf,
+}
+
+impl Command for Ls {
+    fn execute(self) -> Result<()> {
+        for blob in Store::new(self.dir).list()? {
+            print(&blob);
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes unfinished downloads and, optionally, old blobs
+#[derive(Parser, Debug)]
+pub struct Prune {
+    /// The cache directory
+    dir: PathBuf,
+
+    /// Remove blobs unused for longer than this (in seconds)
+    #[clap(long)]
+    max_age: Option<f64>,
+
+    /// Remove the least recently used blobs until the cache fits (in bytes)
+    #[clap(long)]
+    max_size: Option<u64>,
+}
+
+impl Command for Prune {
+    fn execute(self) -> Result<()> {
+        let max_age = self
+            .max_age
+            .map(|secs| {
+                Duration::try_from_secs_f64(secs).map_err(|_| anyhow!("invalid max age: {}", secs))
+            })
+            .transpose()?;
+
+        for blob in Store::new(self.dir).prune(max_age, self.max_size)? {
+            print(&blob);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use super::Command;
+use crate::api::Repository;
+use crate::cmdline::{Cmdline, Efi as Action, Param};
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::raw::{c_int, c_long};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use iocuddle::{Group, Ioctl, Read as Get, Write as Set};
+
+const FS: Group = Group::new(b'f');
+
+// The kernel defines these with `long`, but actually uses `int`. See:
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/fs.h
+const FS_IOC_GETFLAGS: Ioctl<Get, &c_int> = unsafe { FS.read::<c_long>(1).lie() };
+const FS_IOC_SETFLAGS: Ioctl<Set, &c_int> = unsafe { FS.write::<c_long>(2).lie() };
+const FS_IMMUTABLE_FL: c_int = 0x00000010;
+
+/// The EFI variable holding the persisted boot configuration
+pub struct Variable(PathBuf);
+
+impl Variable {
+    const NAME: &'static str = "WyrcanCmdline";
+    const VENDOR: &'static str = "4e4f1c1a-5b3d-4c0e-9a8f-77796e6361e6";
+
+    /// EFI_VARIABLE_NON_VOLATILE | BOOTSERVICE_ACCESS | RUNTIME_ACCESS
+    const ATTRIBUTES: u32 = 0x00000007;
+
+    pub fn new(efivarfs: impl AsRef<Path>) -> Self {
+        let name = format!("{}-{}", Self::NAME, Self::VENDOR);
+        Self(efivarfs.as_ref().join(name))
+    }
+
+    /// Reads the variable, if it exists
+    pub fn read(&self) -> Result<Option<String>> {
+        let mut data = Vec::new();
+        match File::open(&self.0) {
+            Ok(mut file) => file.read_to_end(&mut data)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        // Skip the attributes
+        match data.get(4..) {
+            Some(value) => Ok(Some(std::str::from_utf8(value)?.into())),
+            None => Err(anyhow!("invalid EFI variable: {:?}", self.0)),
+        }
+    }
+
+    /// Creates or replaces the variable
+    pub fn write(&self, value: &str) -> Result<()> {
+        self.unlock()?;
+
+        let mut data = Self::ATTRIBUTES.to_le_bytes().to_vec();
+        data.extend_from_slice(value.as_bytes());
+
+        // efivarfs requires the whole variable in a single write.
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.0)?;
+        if file.write(&data)? != data.len() {
+            return Err(anyhow!("short write to EFI variable: {:?}", self.0));
+        }
+
+        Ok(())
+    }
+
+    /// Removes the variable, if it exists
+    pub fn clear(&self) -> Result<()> {
+        self.unlock()?;
+
+        match std::fs::remove_file(&self.0) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the immutable attribute that efivarfs may set on variables
+    fn unlock(&self) -> Result<()> {
+        let mut file = match File::open(&self.0) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let flags = match FS_IOC_GETFLAGS.ioctl(&file) {
+            Ok((.., flags)) => flags,
+
+            // Not all filesystems support the flags (i.e. during testing).
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOTTY | libc::EOPNOTSUPP)) => {
+                return Ok(())
+            }
+
+            Err(e) => return Err(e.into()),
+        };
+
+        if flags & FS_IMMUTABLE_FL != 0 {
+            FS_IOC_SETFLAGS.ioctl(&mut file, &(flags & !FS_IMMUTABLE_FL))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Persists or clears the boot configuration in EFI NVRAM
+///
+/// This is controlled by the `wyr.efi=write` and `wyr.efi=clear` kernel
+/// cmdline options. Without either option, this command does nothing.
+#[derive(Parser, Debug)]
+pub struct Efi {
+    /// The kernel cmdline to read the configuration from
+    #[clap(long, default_value = "/proc/cmdline")]
+    cmdline: PathBuf,
+
+    /// The efivarfs mount point
+    #[clap(long, default_value = "/sys/firmware/efi/efivars")]
+    efivarfs: PathBuf,
+}
+
+impl Command for Efi {
+    fn execute(self) -> Result<()> {
+        let options = Cmdline::read(&self.cmdline)?.options()?;
+        let variable = Variable::new(&self.efivarfs);
+
+        match options.efi {
+            None => Ok(()),
+            Some(Action::Clear) => variable.clear(),
+            Some(Action::Write) => {
+                let image = options
+                    .img
+                    .ok_or_else(|| anyhow!("no container image specified (wyr.img)"))?;
+                Repository::new(&image, Default::default())?;
+
+                let mut saved = Cmdline::default();
+                saved.push(Param {
+                    key: "wyr.img".into(),
+                    val: Some(image),
+                });
+
+                for arg in options.args {
+                    saved.push(Param {
+                        key: "wyr.arg".into(),
+                        val: Some(arg),
+                    });
+                }
+
+                variable.write(&saved.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Variable;
+
+    #[test]
+    fn roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let variable = Variable::new(dir.path());
+
+        assert_eq!(variable.read().unwrap(), None);
+        variable.write("wyr.img=debian").unwrap();
+        variable.write("wyr.img=fedora wyr.arg=quiet").unwrap();
+        assert_eq!(
+            variable.read().unwrap().as_deref(),
+            Some("wyr.img=fedora wyr.arg=quiet")
+        );
+
+        let raw = std::fs::read(&variable.0).unwrap();
+        assert_eq!(raw[..4], 7u32.to_le_bytes());
+
+        variable.clear().unwrap();
+        variable.clear().unwrap();
+        assert_eq!(variable.read().unwrap(), None);
+    }
+}
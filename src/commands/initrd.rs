@@ -0,0 +1,542 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use clap::ArgEnum;
+use cpio::newc::{trailer, Builder};
+use flate2::write::GzEncoder;
+use libc::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG};
+use log::warn;
+use tar::{Entry, EntryType};
+
+use crate::iotools::Either;
+
+/// A writer which compresses with one of the supported algorithms
+pub type Encoder<W> = Either<GzEncoder<W>, Either<zstd::Encoder<'static, W>, W>>;
+
+/// The compression to apply to an initrd
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Wraps the writer in the appropriate encoder
+    pub fn encoder<W: Write>(compression: Option<Self>, writer: W) -> Result<Encoder<W>> {
+        Ok(match compression {
+            Some(Self::Gzip) => Either::One(GzEncoder::new(writer, Default::default())),
+            Some(Self::Zstd) => Either::Two(Either::One(zstd::Encoder::new(writer, 0)?)),
+            None => Either::Two(Either::Two(writer)),
+        })
+    }
+
+    /// Flushes the encoder and returns the inner writer
+    pub fn finish<W: Write>(encoder: Encoder<W>) -> Result<W> {
+        Ok(match encoder {
+            Either::One(gzip) => gzip.finish()?,
+            Either::Two(Either::One(zstd)) => zstd.finish()?,
+            Either::Two(Either::Two(writer)) => writer,
+        })
+    }
+}
+
+/// The location of a file's contents within the archive
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// A regular file that has been written to the archive
+#[derive(Copy, Clone, Debug)]
+struct Record {
+    extent: Extent,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+}
+
+/// Reads a region of a file without moving its cursor
+struct Region<'a> {
+    file: &'a File,
+    offset: u64,
+    end: u64,
+}
+
+impl Read for Region<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let max = min(buf.len() as u64, self.end - self.offset) as usize;
+        let size = self.file.read_at(&mut buf[..max], self.offset)?;
+        self.offset += size as u64;
+        Ok(size)
+    }
+}
+
+/// Writes tar entries into a newc cpio archive suitable for an initrd
+///
+/// The kernel only links newc entries when the *first* entry of the group
+/// already announces more than one link, which we cannot know in advance
+/// while streaming. So when we encounter a hard link, we write the target
+/// again (announcing the link) and then the link itself. This requires
+/// reading the target back from the uncompressed archive. If no `readback`
+/// handle to the archive is available, hard links are written as absolute
+/// symbolic links instead.
+pub struct Initrd<W: Write> {
+    writer: W,
+    readback: Option<File>,
+    offset: u64,
+    inode: u32,
+    files: HashMap<PathBuf, Record>,
+    links: HashMap<PathBuf, PathBuf>,
+    linked: HashMap<PathBuf, u32>,
+}
+
+impl<W: Write> Initrd<W> {
+    /// The length of the fixed-size newc header
+    const HEADER: u64 = 110;
+
+    /// The maximum number of symbolic links to follow while resolving
+    const MAX_LINKS: usize = 40;
+
+    /// Creates a new archive
+    ///
+    /// If specified, `readback` must refer to the same file as `writer`.
+    pub fn new(writer: W, readback: Option<File>) -> Self {
+        Self {
+            writer,
+            readback,
+            offset: 0,
+            inode: 0,
+            files: HashMap::new(),
+            links: HashMap::new(),
+            linked: HashMap::new(),
+        }
+    }
+
+    fn pad(size: u64) -> u64 {
+        (size + 3) & !3
+    }
+
+    fn normalize(path: &Path) -> Result<PathBuf> {
+        let mut normal = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => normal.push(name),
+                Component::CurDir => continue,
+                _ => return Err(anyhow!("disallowed component in {:?}", path)),
+            }
+        }
+
+        Ok(normal)
+    }
+
+    fn write(
+        &mut self,
+        name: &str,
+        builder: Builder,
+        data: &mut impl Read,
+        size: u64,
+    ) -> Result<Extent> {
+        let len = u32::try_from(size).map_err(|_| anyhow!("file too large: {}", name))?;
+        let offset = self.offset + Self::pad(Self::HEADER + name.len() as u64 + 1);
+
+        let mut writer = builder.write(&mut self.writer, len);
+        let copied = std::io::copy(&mut data.take(size), &mut writer)?;
+        if copied != size {
+            return Err(anyhow!("truncated file: {}", name));
+        }
+
+        writer.finish()?;
+        self.offset = offset + Self::pad(size);
+        Ok(Extent { offset, size })
+    }
+
+    fn inode(&mut self) -> u32 {
+        self.inode += 1;
+        self.inode
+    }
+
+    /// Writes a hard link to a regular file
+    fn link(&mut self, name: &str, target: &Path) -> Result<bool> {
+        let record = match (self.files.get(target), &self.readback) {
+            (Some(record), Some(..)) => *record,
+            _ => return Ok(false),
+        };
+
+        let builder = Builder::new(name)
+            .mode(record.mode)
+            .uid(record.uid)
+            .gid(record.gid)
+            .mtime(record.mtime)
+            .nlink(2);
+
+        // Write the target again, announcing the link.
+        let inode = match self.linked.get(target) {
+            Some(inode) => *inode,
+            None => {
+                self.writer.flush()?;
+                let readback = self.readback.take().unwrap();
+                let mut region = Region {
+                    file: &readback,
+                    offset: record.extent.offset,
+                    end: record.extent.offset + record.extent.size,
+                };
+
+                let inode = self.inode();
+                let tname = target.to_str().unwrap().to_owned();
+                let tbuilder = Builder::new(&tname)
+                    .ino(inode)
+                    .mode(record.mode)
+                    .uid(record.uid)
+                    .gid(record.gid)
+                    .mtime(record.mtime)
+                    .nlink(2);
+
+                let result = self.write(&tname, tbuilder, &mut region, record.extent.size);
+                self.readback = Some(readback);
+                result?;
+
+                self.linked.insert(target.to_owned(), inode);
+                inode
+            }
+        };
+
+        self.write(name, builder.ino(inode), &mut std::io::empty(), 0)?;
+        Ok(true)
+    }
+
+    /// Appends a tar entry to the archive
+    pub fn append(&mut self, entry: &mut Entry<impl Read>) -> Result<()> {
+        let path = Self::normalize(&entry.path()?)?;
+
+        // Skip the root directory
+        if path.as_os_str().is_empty() {
+            return Ok(());
+        }
+
+        // Skip the whiteout files; the unpacker has already applied them
+        if let Some(name) = path.file_name() {
+            if name.to_string_lossy().starts_with(".wh.") {
+                return Ok(());
+            }
+        }
+
+        let name = path
+            .to_str()
+            .ok_or_else(|| anyhow!("non-utf8 path: {:?}", path))?
+            .to_owned();
+
+        let head = entry.header();
+        let perm = u32::try_from(head.mode()?)? & 0o7777;
+        let uid = head.uid()?.try_into()?;
+        let gid = head.gid()?.try_into()?;
+        let mtime = head.mtime()?.try_into().unwrap_or(u32::MAX);
+        let builder = Builder::new(&name).uid(uid).gid(gid).mtime(mtime);
+
+        match head.entry_type() {
+            EntryType::Directory => {
+                let builder = builder.ino(self.inode()).mode(S_IFDIR | perm).nlink(2);
+                self.write(&name, builder, &mut std::io::empty(), 0)?;
+            }
+
+            EntryType::Regular | EntryType::Continuous => {
+                let size = entry.size();
+                let mode = S_IFREG | perm;
+                let builder = builder.ino(self.inode()).mode(mode);
+                let extent = self.write(&name, builder, entry, size)?;
+                let record = Record {
+                    extent,
+                    mode,
+                    uid,
+                    gid,
+                    mtime,
+                };
+
+                self.files.insert(path, record);
+            }
+
+            EntryType::Link => {
+                let link = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("link has no target: {:?}", path))?;
+                let target = Self::normalize(&link)?;
+
+                // Hard links to symbolic links are just symbolic links.
+                let target = match self.links.get(&target).cloned() {
+                    Some(target) => target,
+                    None if self.link(&name, &target)? => return Ok(()),
+                    None => Path::new("/").join(target),
+                };
+
+                self.symlink(&name, builder, path, target)?;
+            }
+
+            EntryType::Symlink => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("link has no target: {:?}", path))?
+                    .into_owned();
+
+                self.symlink(&name, builder, path, target)?;
+            }
+
+            kind @ (EntryType::Char | EntryType::Block | EntryType::Fifo) => {
+                let kind = match kind {
+                    EntryType::Char => S_IFCHR,
+                    EntryType::Block => S_IFBLK,
+                    _ => S_IFIFO,
+                };
+
+                let builder = builder
+                    .ino(self.inode())
+                    .mode(kind | perm)
+                    .rdev_major(head.device_major()?.unwrap_or_default())
+                    .rdev_minor(head.device_minor()?.unwrap_or_default());
+                self.write(&name, builder, &mut std::io::empty(), 0)?;
+            }
+
+            kind => warn!("skipping unsupported entry ({:?}): {:?}", kind, path),
+        }
+
+        Ok(())
+    }
+
+    fn symlink(
+        &mut self,
+        name: &str,
+        builder: Builder,
+        path: PathBuf,
+        target: PathBuf,
+    ) -> Result<()> {
+        let bytes = target
+            .to_str()
+            .ok_or_else(|| anyhow!("non-utf8 link target: {:?}", target))?
+            .as_bytes();
+
+        let builder = builder.ino(self.inode()).mode(S_IFLNK | 0o777);
+        self.write(name, builder, &mut &*bytes, bytes.len() as u64)?;
+        self.links.insert(path, target);
+        Ok(())
+    }
+
+    /// Finds the contents of a regular file, following symbolic links
+    ///
+    /// The path is resolved relative to the root of the archive. The returned
+    /// extent refers to the uncompressed archive.
+    pub fn resolve(&self, path: impl AsRef<Path>) -> Option<Extent> {
+        let mut queue: VecDeque<OsString> =
+            path.as_ref().iter().map(|x| x.to_os_string()).collect();
+
+        let mut links = 0;
+        let mut resolved = PathBuf::new();
+        while let Some(name) = queue.pop_front() {
+            match Path::new(&name).components().next() {
+                Some(Component::Normal(..)) => (),
+                Some(Component::ParentDir) => {
+                    resolved.pop();
+                    continue;
+                }
+                Some(Component::RootDir) => {
+                    resolved = PathBuf::new();
+                    continue;
+                }
+                _ => continue,
+            }
+
+            let next = resolved.join(&name);
+            match self.links.get(&next) {
+                None => resolved = next,
+                Some(target) => {
+                    links += 1;
+                    if links > Self::MAX_LINKS {
+                        return None;
+                    }
+
+                    for component in target.iter().rev() {
+                        queue.push_front(component.to_os_string());
+                    }
+                }
+            }
+        }
+
+        self.files.get(&resolved).map(|x| x.extent)
+    }
+
+    /// Writes the trailer and returns the inner writer
+    pub fn finish(self) -> Result<W> {
+        Ok(trailer(self.writer)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Compression, Extent, Initrd};
+
+    use std::io::{Read, Seek, Write};
+
+    use tar::{Archive, Builder, EntryType, Header};
+
+    fn archive() -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+
+        let mut append = |path: &str, kind: EntryType, link: &str, data: &[u8]| {
+            let mut head = Header::new_gnu();
+            head.set_entry_type(kind);
+            head.set_mode(0o755);
+            head.set_uid(0);
+            head.set_gid(0);
+            head.set_mtime(0);
+            head.set_size(data.len() as u64);
+            if !link.is_empty() {
+                head.set_link_name(link).unwrap();
+            }
+
+            builder.append_data(&mut head, path, data).unwrap();
+        };
+
+        append("./usr", EntryType::Directory, "", b"");
+        append("./usr/lib", EntryType::Directory, "", b"");
+        append("./usr/lib/vmlinuz", EntryType::Regular, "", b"kernel");
+        append("./lib", EntryType::Symlink, "usr/lib", b"");
+        append("./boot", EntryType::Directory, "", b"");
+        append("./boot/vmlinuz", EntryType::Link, "usr/lib/vmlinuz", b"");
+        append(
+            "./boot/wyrcan.kernel",
+            EntryType::Symlink,
+            "../lib/vmlinuz",
+            b"",
+        );
+
+        builder.into_inner().unwrap()
+    }
+
+    fn list(mut output: &[u8]) -> Vec<(String, u32, u32, Vec<u8>)> {
+        let mut entries = Vec::new();
+
+        loop {
+            let mut reader = cpio::NewcReader::new(output).unwrap();
+            if reader.entry().is_trailer() {
+                return entries;
+            }
+
+            let name = reader.entry().name().to_owned();
+            let ino = reader.entry().ino();
+            let nlink = reader.entry().nlink();
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            entries.push((name, ino, nlink, data));
+            output = reader.finish().unwrap();
+        }
+    }
+
+    #[test]
+    fn whiteout() {
+        let mut builder = Builder::new(Vec::new());
+        for path in ["./etc/.wh.passwd", "./var/.wh..wh..opq"] {
+            let mut head = Header::new_gnu();
+            head.set_mode(0o644);
+            head.set_uid(0);
+            head.set_gid(0);
+            head.set_mtime(0);
+            head.set_size(0);
+            builder.append_data(&mut head, path, &[][..]).unwrap();
+        }
+
+        let archive = builder.into_inner().unwrap();
+        let mut archive = Archive::new(&archive[..]);
+        let mut initrd = Initrd::new(Vec::new(), None);
+        for entry in archive.entries().unwrap() {
+            initrd.append(&mut entry.unwrap()).unwrap();
+        }
+
+        let output = initrd.finish().unwrap();
+        let reader = cpio::NewcReader::new(&output[..]).unwrap();
+        assert!(reader.entry().is_trailer());
+    }
+
+    #[test]
+    fn resolve() {
+        let archive = archive();
+        let mut archive = Archive::new(&archive[..]);
+        let mut initrd = Initrd::new(Vec::new(), None);
+        for entry in archive.entries().unwrap() {
+            initrd.append(&mut entry.unwrap()).unwrap();
+        }
+
+        let extent = initrd.resolve("boot/wyrcan.kernel").unwrap();
+        assert_eq!(initrd.resolve("boot/vmlinuz"), Some(extent));
+        assert_eq!(initrd.resolve("/lib/vmlinuz"), Some(extent));
+        assert_eq!(initrd.resolve("boot/missing"), None);
+
+        let output = initrd.finish().unwrap();
+        let Extent { offset, size } = extent;
+        assert_eq!(&output[offset as usize..][..size as usize], b"kernel");
+
+        // Check that the output parses and contains all entries
+        let names: Vec<_> = list(&output).into_iter().map(|x| x.0).collect();
+        assert_eq!(
+            names,
+            [
+                "usr",
+                "usr/lib",
+                "usr/lib/vmlinuz",
+                "lib",
+                "boot",
+                "boot/vmlinuz",
+                "boot/wyrcan.kernel"
+            ]
+        );
+    }
+
+    #[test]
+    fn hardlink() {
+        let file = tempfile::tempfile().unwrap();
+
+        let archive = archive();
+        let mut archive = Archive::new(&archive[..]);
+        let mut initrd = Initrd::new(file.try_clone().unwrap(), Some(file));
+        for entry in archive.entries().unwrap() {
+            initrd.append(&mut entry.unwrap()).unwrap();
+        }
+
+        let mut file = initrd.finish().unwrap();
+        let mut output = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut output).unwrap();
+
+        // The target is written again, announcing the link.
+        let entries = list(&output);
+        let target = &entries[5];
+        let link = &entries[6];
+        assert_eq!(target.0, "usr/lib/vmlinuz");
+        assert_eq!(target.2, 2);
+        assert_eq!(target.3, b"kernel");
+        assert_eq!(link.0, "boot/vmlinuz");
+        assert_eq!(link.1, target.1);
+        assert_eq!(link.2, 2);
+        assert!(link.3.is_empty());
+    }
+
+    #[test]
+    fn compression() {
+        let mut encoder = Compression::encoder(Some(Compression::Zstd), Vec::new()).unwrap();
+        encoder.write_all(b"070701").unwrap();
+        let output = Compression::finish(encoder).unwrap();
+        assert_eq!(zstd::decode_all(&output[..]).unwrap(), b"070701");
+
+        let mut encoder = Compression::encoder(Some(Compression::Gzip), Vec::new()).unwrap();
+        encoder.write_all(b"070701").unwrap();
+        let output = Compression::finish(encoder).unwrap();
+        assert_eq!(&output[..2], b"\x1f\x8b");
+    }
+}
@@ -1,6 +1,12 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
+mod boot;
+mod cache;
+mod efi;
+mod initrd;
+mod net;
+mod registry;
 mod unpack;
 mod unpacker;
 
@@ -13,12 +19,21 @@ pub trait Command {
 #[derive(Parser, Debug)]
 #[clap(about = "The Container Bootloader")]
 pub enum Main {
+    Boot(boot::Boot),
+    #[clap(subcommand)]
+    Cache(cache::Cache),
+    Efi(efi::Efi),
+    Net(net::Net),
     Unpack(unpack::Unpack),
 }
 
 impl Command for Main {
     fn execute(self) -> anyhow::Result<()> {
         match self {
+            Self::Boot(cmd) => cmd.execute(),
+            Self::Cache(cmd) => cmd.execute(),
+            Self::Efi(cmd) => cmd.execute(),
+            Self::Net(cmd) => cmd.execute(),
             Self::Unpack(cmd) => cmd.execute(),
         }
     }
@@ -0,0 +1,187 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use super::Command;
+use crate::cmdline::Cmdline;
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+
+/// The kinds of systemd-networkd configuration files
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    Network,
+    NetDev,
+    Link,
+}
+
+impl FromStr for Kind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "network" => Ok(Self::Network),
+            "netdev" => Ok(Self::NetDev),
+            "link" => Ok(Self::Link),
+            _ => Err(anyhow!("unknown network file kind: {}", s)),
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Network => write!(f, "network"),
+            Self::NetDev => write!(f, "netdev"),
+            Self::Link => write!(f, "link"),
+        }
+    }
+}
+
+/// A single systemd-networkd file, with sections and keys in cmdline order
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct File(Vec<(String, Vec<(String, String)>)>);
+
+impl Display for File {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (section, keys)) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            writeln!(f, "[{}]", section)?;
+            for (key, val) in keys {
+                writeln!(f, "{}={}", key, val)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl File {
+    fn push(&mut self, section: &str, key: &str, val: &str) {
+        let index = match self.0.iter().position(|(s, ..)| s == section) {
+            Some(index) => index,
+            None => {
+                self.0.push((section.into(), Vec::new()));
+                self.0.len() - 1
+            }
+        };
+
+        self.0[index].1.push((key.into(), val.into()));
+    }
+}
+
+/// Writes systemd-networkd configuration from the kernel cmdline
+///
+/// Each `wyr.net.[KIND.]FILE.SECTION.KEY=VAL` option adds `KEY=VAL` to
+/// `[SECTION]` in `FILE.KIND`. When omitted, `KIND` defaults to `network`.
+#[derive(Parser, Debug)]
+pub struct Net {
+    /// The kernel cmdline to read the configuration from
+    #[clap(long, default_value = "/proc/cmdline")]
+    cmdline: PathBuf,
+
+    /// The directory to write the configuration files into
+    #[clap(long, default_value = "/etc/systemd/network")]
+    output: PathBuf,
+}
+
+impl Net {
+    const PREFIX: &'static str = "wyr.net.";
+
+    fn parse(options: &[(String, String)]) -> Result<BTreeMap<String, File>> {
+        let mut files = BTreeMap::<String, File>::new();
+
+        for (name, val) in options {
+            let parts: Vec<&str> = name.split('.').collect();
+            let (kind, file, section, key) = match parts[..] {
+                [file, section, key] => (Kind::Network, file, section, key),
+                [kind, file, section, key] => (kind.parse()?, file, section, key),
+                _ => return Err(anyhow!("invalid network option: {}{}", Self::PREFIX, name)),
+            };
+
+            // The file name must not escape the output directory.
+            for part in [file, section, key] {
+                if part.is_empty() || part.contains(['/', '\0']) {
+                    return Err(anyhow!("invalid network option: {}{}", Self::PREFIX, name));
+                }
+            }
+
+            let name = format!("{}.{}", file, kind);
+            files.entry(name).or_default().push(section, key, val);
+        }
+
+        Ok(files)
+    }
+}
+
+impl Command for Net {
+    fn execute(self) -> Result<()> {
+        let options = Cmdline::read(&self.cmdline)?.options()?;
+        let files = Self::parse(&options.net)?;
+
+        if !files.is_empty() {
+            std::fs::create_dir_all(&self.output)?;
+        }
+
+        for (name, file) in files {
+            std::fs::write(self.output.join(name), file.to_string())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Net;
+    use crate::cmdline::Cmdline;
+
+    fn render(cmdline: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let files = Net::parse(&cmdline.parse::<Cmdline>()?.options()?.net)?;
+        Ok(files.into_iter().map(|(k, v)| (k, v.to_string())).collect())
+    }
+
+    #[test]
+    fn parse() {
+        let cmdline = "quiet wyr.img=debian \
+            wyr.net.eth0.Match.Name=eth0 \
+            wyr.net.eth0.Network.Address=10.0.0.2/24 \
+            wyr.net.eth0.Match.Type=ether \
+            wyr.net.eth0.Network.Address=10.0.0.3/24 \
+            wyr.net.netdev.br0.NetDev.Kind=bridge \
+            wyr.net.link.eth0.Link.MTUBytes=9000";
+
+        let files = render(cmdline).unwrap();
+        let names: Vec<&str> = files.iter().map(|(k, ..)| k.as_str()).collect();
+        assert_eq!(names, ["br0.netdev", "eth0.link", "eth0.network"]);
+
+        assert_eq!(files[0].1, "[NetDev]\nKind=bridge\n");
+        assert_eq!(
+            files[2].1,
+            "[Match]\nName=eth0\nType=ether\n\n\
+             [Network]\nAddress=10.0.0.2/24\nAddress=10.0.0.3/24\n"
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        for cmdline in [
+            "wyr.net.bogus.eth0.Match.Name=eth0",
+            "wyr.net..Match.Name=eth0",
+            "wyr.net.eth0.Match.Name",
+            "wyr.net.eth0.Name=eth0",
+            "wyr.net.a.b.c.d.e=f",
+            "\"wyr.net.../etc/passwd.Match.Name=eth0\"",
+        ] {
+            assert!(render(cmdline).is_err(), "{}", cmdline);
+        }
+    }
+}
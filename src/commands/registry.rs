@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use crate::api::{Auths, Cache, Config, Mirrors, Policy, Retry};
+use crate::cmdline::Options;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use clap::Args;
+
+/// Options for accessing container registries
+#[derive(Args, Debug)]
+pub struct Registry {
+    /// Read registry credentials from this file (format: Docker config.json)
+    #[clap(long)]
+    authfile: Vec<PathBuf>,
+
+    /// Allow plain HTTP for this registry (format: host[:port])
+    #[clap(long)]
+    insecure: Vec<String>,
+
+    /// Trust the CA certificates in this file (format: PEM)
+    #[clap(long)]
+    ca_file: Vec<PathBuf>,
+
+    /// Read per-registry certificates from this directory (format: containers-certs.d)
+    #[clap(long, default_value = "/etc/containers/certs.d")]
+    certs_dir: Vec<PathBuf>,
+
+    /// Read registry mirrors from this file (format: containers-registries.conf)
+    #[clap(long, default_value = "/etc/containers/registries.conf")]
+    registries_conf: Vec<PathBuf>,
+
+    /// Connect to registries through this proxy (default: $HTTPS_PROXY)
+    #[clap(long)]
+    proxy: Option<String>,
+
+    /// Don't use the proxy for this host or domain (default: $NO_PROXY)
+    #[clap(long)]
+    no_proxy: Vec<String>,
+
+    /// Retry failed requests and interrupted downloads this many times [default: 5]
+    #[clap(long)]
+    retries: Option<u32>,
+
+    /// Wait this long before the first retry, doubling for each further retry (in seconds) [default: 1]
+    #[clap(long)]
+    retry_backoff: Option<f64>,
+
+    /// Give up on a connection after this long without progress (in seconds) [default: 60]
+    #[clap(long)]
+    timeout: Option<f64>,
+
+    /// Give up on all registry requests after this long (in seconds)
+    #[clap(long)]
+    deadline: Option<f64>,
+
+    /// Look for blobs in this directory before downloading them, and store them there (format: OCI image layout)
+    #[clap(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// When to use the cache instead of the registry (prefer-network, prefer-cache or cache-only) [default: prefer-network]
+    #[clap(long)]
+    cache_policy: Option<Policy>,
+}
+
+impl Registry {
+    /// The default for `--timeout`
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Converts an optional number of seconds
+    fn secs(secs: Option<f64>, name: &str) -> Result<Option<Duration>> {
+        secs.map(|secs| {
+            Duration::try_from_secs_f64(secs).map_err(|_| anyhow!("invalid {}: {}", name, secs))
+        })
+        .transpose()
+    }
+
+    /// Gets the first non-empty environment variable
+    fn env(names: &[&str]) -> Option<String> {
+        names
+            .iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .find(|val| !val.is_empty())
+    }
+
+    /// Builds the registry configuration
+    ///
+    /// Credentials from the cmdline take precedence over those from files
+    /// and, like other options, later values override earlier ones.
+    pub fn config(&self, options: &Options) -> Result<Arc<Config>> {
+        let mut auths = Auths::default();
+
+        for (host, credentials) in options.auth.iter().rev() {
+            auths.insert(host, credentials.clone());
+        }
+
+        for path in &self.authfile {
+            auths.read(path)?;
+        }
+
+        auths.load();
+
+        // Mirrors are tried in order, so those from the cmdline come first.
+        let mut mirrors = Mirrors::default();
+        for (prefix, mirror) in &options.mirror {
+            mirrors.insert(prefix, mirror.clone());
+        }
+
+        for path in self.registries_conf.iter().filter(|p| p.exists()) {
+            mirrors.read(path)?;
+        }
+
+        let proxy = options
+            .proxy
+            .clone()
+            .or_else(|| self.proxy.clone())
+            .or_else(|| Self::env(&["HTTPS_PROXY", "https_proxy"]));
+
+        let env = Self::env(&["NO_PROXY", "no_proxy"]).unwrap_or_default();
+        let no_proxy = options.noproxy.iter().chain(&self.no_proxy).cloned();
+        let no_proxy = no_proxy.chain(env.split(',').map(String::from));
+
+        let default = Retry::default();
+        let backoff = Self::secs(self.retry_backoff, "retry backoff")?;
+        let retry = Retry {
+            attempts: options.retries.or(self.retries).unwrap_or(default.attempts),
+            backoff: options.backoff.or(backoff).unwrap_or(default.backoff),
+        };
+
+        let timeout = Self::secs(self.timeout, "timeout")?;
+        let timeout = options.timeout.or(timeout).unwrap_or(Self::TIMEOUT);
+
+        // The deadline counts from now, before anything was downloaded.
+        let deadline = Self::secs(self.deadline, "deadline")?;
+        let deadline = options.deadline.or(deadline).map(|d| Instant::now() + d);
+
+        let insecure = options.insecure.iter().chain(&self.insecure).cloned();
+        let insecure = insecure.chain(mirrors.insecure().map(String::from));
+        let cas = options.ca.iter().chain(&self.ca_file).cloned();
+        let certs = options.certs.iter().chain(&self.certs_dir).cloned();
+
+        Ok(Arc::new(Config {
+            auths,
+            insecure: insecure.collect(),
+            cas: cas.collect(),
+            certs: certs.collect(),
+            mirrors,
+            proxy,
+            no_proxy: no_proxy.collect(),
+            retry,
+            timeout: Some(timeout),
+            deadline,
+            cache: self.cache_dir.clone().map(Cache::new),
+            policy: options
+                .cachepolicy
+                .or(self.cache_policy)
+                .unwrap_or_default(),
+        }))
+    }
+}
@@ -1,11 +1,15 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
+use super::initrd::{Compression, Initrd};
+use super::registry::Registry;
 use super::unpacker::Unpacker;
 use super::Command;
-use crate::api::Repository;
+use crate::api::Image;
+use crate::cmdline::Options;
+use crate::formats::docker::v2::Platform;
 
-use std::fs::{DirBuilder, OpenOptions};
+use std::fs::{DirBuilder, File, OpenOptions};
 use std::io::Error;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
@@ -16,28 +20,88 @@ use clap::Parser;
 use libc::{mode_t, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK};
 use log::warn;
 
-/// Unpacks a container into the given directory
+/// Unpacks a container into the given directory or initrd
 #[derive(Parser, Debug)]
 pub struct Unpack {
-    /// The container image (format: [source]name[:tag|@digest])
+    /// The container image (format: [source]name[:tag|@digest], oci:PATH[:REF]
+    /// or docker-archive:PATH[:NAME:TAG])
     image: String,
 
-    /// The output directory (will be created)
+    /// The output directory or initrd file (will be created)
     output: PathBuf,
 
+    /// Write a (newc cpio) initrd instead of a directory
+    #[clap(long)]
+    initrd: bool,
+
+    /// Compress the initrd
+    #[clap(long, arg_enum, requires = "initrd")]
+    compress: Option<Compression>,
+
     /// Don't display the progress bar
     #[clap(short, long)]
     quiet: bool,
+
+    /// The platform to unpack from a multi-platform image (format: os/arch[/variant])
+    #[clap(long)]
+    platform: Option<Platform>,
+
+    #[clap(flatten)]
+    registry: Registry,
 }
 
 impl Command for Unpack {
     fn execute(self) -> Result<()> {
-        std::fs::create_dir(&self.output)?;
+        // Create the output first so that we fail before downloading.
+        let file = match self.initrd {
+            false => {
+                std::fs::create_dir(&self.output)?;
+                None
+            }
+
+            true => Some(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(&self.output)?,
+            ),
+        };
 
-        let (repo, tag) = Repository::new(&self.image)?;
-        let image = repo.image(tag)?;
+        let config = self.registry.config(&Options::default())?;
+        let platform = self.platform.clone().unwrap_or_else(Platform::host);
+        let image = Image::open(&self.image, config, &platform)?;
         let unpacker = Unpacker::new(&image, !self.quiet)?;
 
+        match file {
+            Some(file) => self.initrd(&unpacker, file),
+            None => self.directory(&unpacker),
+        }
+    }
+}
+
+impl Unpack {
+    fn initrd(&self, unpacker: &Unpacker, file: File) -> Result<()> {
+        // We can only read back from the archive if it is uncompressed, so
+        // compressed archives get symbolic links instead of hard links.
+        let readback = match self.compress {
+            None => Some(file.try_clone()?),
+            Some(..) => None,
+        };
+
+        let encoder = Compression::encoder(self.compress, file)?;
+        let mut initrd = Initrd::new(encoder, readback);
+        for mut bundle in unpacker.bundles()? {
+            for entry in bundle.entries()? {
+                initrd.append(&mut entry?)?;
+            }
+        }
+
+        Compression::finish(initrd.finish()?)?.sync_all()?;
+        Ok(())
+    }
+
+    fn directory(&self, unpacker: &Unpacker) -> Result<()> {
         for mut bundle in unpacker.bundles()? {
             for entry in bundle.entries()? {
                 let mut entry = entry?;
@@ -129,3 +193,130 @@ impl Command for Unpack {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Unpack;
+    use crate::commands::Command;
+    use crate::formats::docker::v2::Platform;
+    use crate::formats::oci::{self, Descriptor, Index, REF_NAME};
+    use crate::formats::{Digest, Manifest};
+
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::path::Path;
+
+    use clap::Parser;
+    use flate2::read::GzDecoder;
+    use tar::{Builder, EntryType, Header};
+
+    /// Writes an image layout holding a single image with the given layer
+    fn layout(dir: &Path, layer: &[u8]) {
+        let blob = |data: &[u8]| {
+            let digest = Digest::sha256(data);
+            std::fs::create_dir_all(dir.join("blobs/sha256")).unwrap();
+            std::fs::write(
+                dir.join("blobs/sha256").join(&digest.to_string()[7..]),
+                data,
+            )
+            .unwrap();
+            Descriptor {
+                media_type: String::new(),
+                digest,
+                size: data.len() as u64,
+                urls: Vec::new(),
+                annotations: HashMap::new(),
+                platform: None,
+            }
+        };
+
+        let manifest = oci::Manifest {
+            schema_version: 2,
+            media_type: Some(Manifest::OCI.into()),
+            config: Descriptor {
+                media_type: "application/vnd.oci.image.config.v1+json".into(),
+                ..blob(b"{}")
+            },
+            layers: vec![Descriptor {
+                media_type: "application/vnd.oci.image.layer.v1.tar".into(),
+                ..blob(layer)
+            }],
+            annotations: HashMap::new(),
+        };
+
+        let index = Index {
+            schema_version: 2,
+            media_type: None,
+            manifests: vec![Descriptor {
+                media_type: Manifest::OCI.into(),
+                annotations: [(REF_NAME.into(), "latest".into())].into(),
+                platform: Some(Platform::host()),
+                ..blob(&serde_json::to_vec(&manifest).unwrap())
+            }],
+            annotations: HashMap::new(),
+        };
+
+        std::fs::write(dir.join("index.json"), serde_json::to_vec(&index).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn compressed_hardlink() {
+        let header = |kind, size| {
+            let mut head = Header::new_gnu();
+            head.set_entry_type(kind);
+            head.set_mode(0o644);
+            head.set_uid(0);
+            head.set_gid(0);
+            head.set_mtime(0);
+            head.set_size(size);
+            head
+        };
+
+        let mut builder = Builder::new(Vec::new());
+        let mut head = header(EntryType::Regular, 5);
+        builder
+            .append_data(&mut head, "hello", &b"hello"[..])
+            .unwrap();
+        let mut head = header(EntryType::Link, 0);
+        builder.append_link(&mut head, "link", "hello").unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image");
+        let output = dir.path().join("initrd");
+        layout(&image, &builder.into_inner().unwrap());
+
+        let image = format!("oci:{}", image.display());
+        let args = ["unpack", "--initrd", "--compress", "gzip", "-q", &image];
+        let unpack = Unpack::try_parse_from(args.iter().copied().chain(output.to_str())).unwrap();
+        unpack.execute().unwrap();
+
+        let mut data = Vec::new();
+        let file = std::fs::File::open(&output).unwrap();
+        GzDecoder::new(file).read_to_end(&mut data).unwrap();
+
+        // Nothing but the output is written.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        let mut data = &data[..];
+        let mut entries = Vec::new();
+        loop {
+            let mut reader = cpio::NewcReader::new(data).unwrap();
+            if reader.entry().is_trailer() {
+                break;
+            }
+
+            let name = reader.entry().name().to_owned();
+            let mode = reader.entry().mode();
+            let mut body = Vec::new();
+            reader.read_to_end(&mut body).unwrap();
+            entries.push((name, mode, body));
+            data = reader.finish().unwrap();
+        }
+
+        // The archive can't be read back, so the link becomes a symbolic link.
+        let (target, link) = (&entries[entries.len() - 2], &entries[entries.len() - 1]);
+        assert_eq!((target.0.as_str(), &target.2[..]), ("hello", &b"hello"[..]));
+        assert_eq!((link.0.as_str(), &link.2[..]), ("link", &b"/hello"[..]));
+        assert_eq!(link.1 & libc::S_IFMT, libc::S_IFLNK);
+    }
+}
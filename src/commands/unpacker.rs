@@ -22,7 +22,7 @@ pub struct Bundle<'a, T: Read> {
 }
 
 impl<'a, T: Read> Bundle<'a, T> {
-    pub fn entries(&mut self) -> Result<impl Iterator<Item = Result<Entry<impl Read>>>> {
+    pub fn entries(&mut self) -> Result<impl Iterator<Item = Result<Entry<'_, impl Read>>>> {
         Ok(self
             .archive
             .entries()?
@@ -62,7 +62,7 @@ impl Unpacker {
         })
     }
 
-    pub fn bundles(&self) -> Result<Vec<Bundle<impl Read>>> {
+    pub fn bundles(&self) -> Result<Vec<Bundle<'_, impl Read>>> {
         // Start ALL downloads in separate threads
         // We collect here to start all the threads.
         #[allow(clippy::needless_collect)]
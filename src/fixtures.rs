@@ -0,0 +1,66 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+//! A registry for tests
+
+use crate::formats::{Digest, Manifest};
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+
+/// Serves manifests from a registry on a loopback port (format: path => body)
+///
+/// Returns the host and a log of the requests (format: `METHOD PATH`).
+/// Connections over TLS are closed, so that clients fall back to HTTP.
+pub fn registry(manifests: HashMap<String, String>) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let requests = log.clone();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                match stream.read(&mut byte) {
+                    Ok(1) if head.is_empty() && byte[0] == 0x16 => break,
+                    Ok(1) => head.push(byte[0]),
+                    _ => break,
+                }
+            }
+
+            let head = String::from_utf8_lossy(&head);
+            let mut line = head.split(' ');
+            let (method, path) = match (line.next(), line.next()) {
+                (Some(method), Some(path)) => (method, path),
+                _ => continue,
+            };
+            log.lock().unwrap().push(format!("{} {}", method, path));
+
+            let (status, body) = match manifests.get(path) {
+                Some(body) => ("200 OK", body.as_str()),
+                None => ("404 Not Found", ""),
+            };
+
+            let mut response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nDocker-Content-Digest: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                Manifest::OCI,
+                Digest::sha256(body.as_bytes()),
+                body.len()
+            );
+
+            if method != "HEAD" {
+                response.push_str(body);
+            }
+
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+
+    (host, requests)
+}
@@ -6,7 +6,7 @@ use std::str::FromStr;
 
 use anyhow::Result;
 use ring::digest::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::iotools::Validatable;
 
@@ -65,6 +65,24 @@ impl std::fmt::Display for Invalid {
     }
 }
 
+/// The data does not match the expected digest
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::error::Error for Mismatch {}
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "digest mismatch (expected: {}, actual: {})",
+            self.expected, self.actual
+        )
+    }
+}
+
 impl FromStr for Inner {
     type Err = Invalid;
 
@@ -142,6 +160,20 @@ impl<'de> Deserialize<'de> for Digest {
     }
 }
 
+impl Serialize for Digest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl PartialEq for Digest {
+    fn eq(&self, other: &Self) -> bool {
+        self.algorithm() == other.algorithm() && self.0.as_ref() == other.0.as_ref()
+    }
+}
+
+impl Eq for Digest {}
+
 impl FromStr for Digest {
     type Err = Invalid;
 
@@ -158,6 +190,33 @@ impl Digest {
             Inner::Sha512(..) => "sha512",
         }
     }
+
+    /// Computes the SHA-256 digest of `data`
+    pub fn sha256(data: &[u8]) -> Self {
+        let mut hash = [0; SHA256_OUTPUT_LEN];
+        hash.copy_from_slice(digest(&SHA256, data).as_ref());
+        Self(Inner::Sha256(Context::new(&SHA256), hash))
+    }
+
+    /// Checks that `data` hashes to this digest
+    pub fn verify(&self, data: &[u8]) -> Result<(), Mismatch> {
+        let hash = match self.0 {
+            Inner::Sha256(..) => digest(&SHA256, data),
+            Inner::Sha384(..) => digest(&SHA384, data),
+            Inner::Sha512(..) => digest(&SHA512, data),
+        };
+
+        let mut actual = self.clone();
+        actual.0.as_mut().copy_from_slice(hash.as_ref());
+        if actual != *self {
+            return Err(Mismatch {
+                expected: self.to_string(),
+                actual: actual.to_string(),
+            });
+        }
+
+        Ok(())
+    }
 }
 
 impl Write for Digest {
@@ -197,3 +256,24 @@ impl Validatable for Digest {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Digest;
+
+    const EMPTY: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn verify() {
+        let digest: Digest = EMPTY.parse().unwrap();
+        assert_eq!(digest.to_string(), EMPTY);
+        assert!(digest.verify(b"").is_ok());
+
+        assert_eq!(Digest::sha256(b""), digest);
+
+        let err = digest.verify(b"foo").unwrap_err();
+        assert_eq!(err.expected, EMPTY);
+        assert_ne!(err.actual, EMPTY);
+        assert!(err.actual.starts_with("sha256:"));
+    }
+}
@@ -0,0 +1,42 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::formats::Digest;
+
+/// An image in the `manifest.json` of a `docker save` tarball
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Item {
+    /// The path of the image configuration
+    pub config: String,
+
+    #[serde(default)]
+    pub repo_tags: Option<Vec<String>>,
+
+    /// The paths of the layers, from the bottom up
+    pub layers: Vec<String>,
+}
+
+/// The legacy `repositories` file: the top layer id, by tag and repository
+pub type Repositories = HashMap<String, HashMap<String, String>>;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RootFs {
+    #[allow(dead_code)]
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    /// The digests of the uncompressed layers, from the bottom up
+    #[serde(default)]
+    pub diff_ids: Vec<Digest>,
+}
+
+/// An image configuration, as far as the layers are concerned
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub rootfs: RootFs,
+}
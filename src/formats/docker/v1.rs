@@ -16,18 +16,23 @@ pub struct Layer {
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Manifest {
+    #[allow(dead_code)]
     #[serde(rename = "schemaVersion")]
     pub schema_version: usize,
 
+    #[allow(dead_code)]
     pub name: String,
 
+    #[allow(dead_code)]
     pub tag: String,
 
+    #[allow(dead_code)]
     pub architecture: String,
 
     #[serde(rename = "fsLayers")]
     pub layers: Vec<Layer>,
 
+    #[allow(dead_code)]
     #[serde(default)]
     pub history: Vec<History>,
 }
@@ -3,31 +3,130 @@
 
 use super::super::Digest;
 
-use serde::Deserialize;
+use std::fmt::Display;
+use std::str::FromStr;
 
-#[derive(Clone, Debug, Deserialize)]
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Platform {
     pub architecture: String,
 
     pub os: String,
 
-    #[serde(rename = "os.version")]
+    #[serde(rename = "os.version", skip_serializing_if = "Option::is_none")]
     pub os_version: Option<String>,
 
-    #[serde(default, rename = "os.features")]
+    #[serde(default, rename = "os.features", skip_serializing_if = "Vec::is_empty")]
     pub os_features: Vec<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub variant: Option<String>,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub features: Vec<String>,
 }
 
+impl Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+
+        let os = parts.next().filter(|x| !x.is_empty());
+        let arch = parts.next().filter(|x| !x.is_empty());
+        let variant = parts.next().filter(|x| !x.is_empty());
+        let (os, arch) = match (os, arch, parts.next()) {
+            (Some(os), Some(arch), None) => (os, arch),
+            _ => {
+                return Err(anyhow!(
+                    "invalid platform (format: os/arch[/variant]): {}",
+                    s
+                ))
+            }
+        };
+
+        Ok(Self {
+            architecture: arch.into(),
+            os: os.into(),
+            os_version: None,
+            os_features: Vec::new(),
+            variant: variant.map(Into::into),
+            features: Vec::new(),
+        })
+    }
+}
+
+impl Platform {
+    /// The platform of the running host, using the Go naming conventions
+    pub fn host() -> Self {
+        let arch = match std::env::consts::ARCH {
+            "x86" => "386",
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+            "mips64" if cfg!(target_endian = "little") => "mips64le",
+            arch => arch,
+        };
+
+        let variant = match arch {
+            "arm64" => Some("v8"),
+            "arm" if cfg!(target_feature = "v7") => Some("v7"),
+            _ => None,
+        };
+
+        Self {
+            architecture: arch.into(),
+            os: std::env::consts::OS.into(),
+            os_version: None,
+            os_features: Vec::new(),
+            variant: variant.map(Into::into),
+            features: Vec::new(),
+        }
+    }
+
+    fn normalized_variant(&self) -> Option<&str> {
+        match (self.architecture.as_str(), self.variant.as_deref()) {
+            ("arm64", None) => Some("v8"),
+            (.., variant) => variant,
+        }
+    }
+
+    /// Whether a manifest for the `other` platform can run on this one
+    ///
+    /// If this platform does not specify a variant, any variant matches.
+    pub fn matches(&self, other: &Platform) -> bool {
+        if self.os != other.os || self.architecture != other.architecture {
+            return false;
+        }
+
+        match self.variant {
+            None => true,
+            Some(..) => self.normalized_variant() == other.normalized_variant(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Item {
+    #[allow(dead_code)]
     #[serde(rename = "mediaType")]
     pub media_type: Option<String>,
 
+    #[allow(dead_code)]
     pub size: u64,
 
     pub digest: Digest,
@@ -37,9 +136,11 @@ pub struct Item {
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct ManifestList {
+    #[allow(dead_code)]
     #[serde(rename = "schemaVersion")]
     pub schema_version: usize,
 
+    #[allow(dead_code)]
     #[serde(rename = "mediaType")]
     pub media_type: Option<String>,
 
@@ -48,11 +149,14 @@ pub struct ManifestList {
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
+    #[allow(dead_code)]
     #[serde(rename = "mediaType")]
     pub media_type: Option<String>,
 
+    #[allow(dead_code)]
     pub size: u64,
 
+    #[allow(dead_code)]
     pub digest: Digest,
 }
 
@@ -71,14 +175,55 @@ pub struct Layer {
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Manifest {
+    #[allow(dead_code)]
     #[serde(rename = "schemaVersion")]
     pub schema_version: usize,
 
+    #[allow(dead_code)]
     #[serde(rename = "mediaType")]
     pub media_type: Option<String>,
 
+    #[allow(dead_code)]
     pub config: Config,
 
     #[serde(default)]
     pub layers: Vec<Layer>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::Platform;
+
+    #[test]
+    fn parse() {
+        let platform: Platform = "linux/arm64/v8".parse().unwrap();
+        assert_eq!(platform.os, "linux");
+        assert_eq!(platform.architecture, "arm64");
+        assert_eq!(platform.variant.as_deref(), Some("v8"));
+        assert_eq!(platform.to_string(), "linux/arm64/v8");
+
+        let platform: Platform = "linux/amd64".parse().unwrap();
+        assert_eq!(platform.variant, None);
+        assert_eq!(platform.to_string(), "linux/amd64");
+
+        assert!("linux".parse::<Platform>().is_err());
+        assert!("linux//v7".parse::<Platform>().is_err());
+        assert!("linux/arm/v7/x".parse::<Platform>().is_err());
+    }
+
+    #[test]
+    fn matches() {
+        let arm64: Platform = "linux/arm64".parse().unwrap();
+        let arm64v8: Platform = "linux/arm64/v8".parse().unwrap();
+        let armv6: Platform = "linux/arm/v6".parse().unwrap();
+        let armv7: Platform = "linux/arm/v7".parse().unwrap();
+        let amd64: Platform = "linux/amd64".parse().unwrap();
+
+        assert!(arm64.matches(&arm64v8));
+        assert!(arm64v8.matches(&arm64));
+        assert!(!arm64.matches(&amd64));
+        assert!(!armv7.matches(&armv6));
+        assert!("linux/arm".parse::<Platform>().unwrap().matches(&armv6));
+        assert!(!"windows/amd64".parse::<Platform>().unwrap().matches(&amd64));
+    }
+}
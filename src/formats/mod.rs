@@ -7,21 +7,221 @@ pub mod oci;
 
 pub use self::digest::Digest;
 
+use anyhow::{anyhow, Result};
+use serde::de::IgnoredAny;
 use serde::Deserialize;
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
 pub enum Manifest {
-    #[serde(rename = "application/vnd.docker.distribution.manifest.v1+json")]
-    #[serde(alias = "application/vnd.docker.distribution.manifest.v1+prettyjws")]
     DockerV1(docker::v1::Manifest),
-
-    #[serde(rename = "application/vnd.docker.distribution.manifest.v2+json")]
     DockerV2(docker::v2::Manifest),
-
-    #[serde(rename = "application/vnd.docker.distribution.manifest.list.v2+json")]
     DockerV2List(docker::v2::ManifestList),
-
-    #[serde(rename = "application/vnd.oci.image.manifest.v1+json")]
     Oci(oci::Manifest),
+    OciIndex(oci::Index),
+}
+
+impl Manifest {
+    pub const DOCKER_V1: &'static str = "application/vnd.docker.distribution.manifest.v1+json";
+    pub const DOCKER_V1_SIGNED: &'static str =
+        "application/vnd.docker.distribution.manifest.v1+prettyjws";
+    pub const DOCKER_V2: &'static str = "application/vnd.docker.distribution.manifest.v2+json";
+    pub const DOCKER_V2_LIST: &'static str =
+        "application/vnd.docker.distribution.manifest.list.v2+json";
+    pub const OCI: &'static str = "application/vnd.oci.image.manifest.v1+json";
+    pub const OCI_INDEX: &'static str = "application/vnd.oci.image.index.v1+json";
+
+    /// All supported manifest media types, in order of preference
+    pub const MEDIA_TYPES: &'static [&'static str] = &[
+        Self::OCI_INDEX,
+        Self::DOCKER_V2_LIST,
+        Self::OCI,
+        Self::DOCKER_V2,
+        Self::DOCKER_V1_SIGNED,
+        Self::DOCKER_V1,
+    ];
+
+    /// The maximum size of a manifest (or of other image metadata)
+    pub const MAX_SIZE: u64 = 4 * 1024 * 1024;
+
+    /// Media types that say nothing about the kind of manifest
+    const GENERIC: &'static [&'static str] = &["application/json", "text/plain"];
+
+    /// Parses a manifest of the given media type
+    ///
+    /// The media type is usually the `Content-Type` of the response. When it
+    /// is missing or generic (i.e. `application/json`), the media type is
+    /// detected from the manifest body instead. If both are present, they
+    /// must agree.
+    pub fn parse(media_type: Option<&str>, body: &[u8]) -> Result<Self> {
+        let media_type = media_type
+            .map(|x| x.split(';').next().unwrap_or_default().trim())
+            .map(|x| x.to_ascii_lowercase())
+            .filter(|x| !x.is_empty() && !Self::GENERIC.contains(&x.as_str()));
+
+        if let Some(media_type) = &media_type {
+            if !Self::MEDIA_TYPES.contains(&media_type.as_str()) {
+                return Err(anyhow!("unsupported manifest type: {}", media_type));
+            }
+        }
+
+        let media_type = match (media_type, Self::detect(body)?) {
+            // Signatures can't be detected, so schema 1 is schema 1.
+            (Some(lhs), Some(rhs)) if lhs == Self::DOCKER_V1_SIGNED && rhs == Self::DOCKER_V1 => {
+                lhs
+            }
+
+            (Some(lhs), Some(rhs)) if lhs != rhs => {
+                return Err(anyhow!("manifest type mismatch: {} != {}", lhs, rhs))
+            }
+
+            (Some(media_type), ..) | (None, Some(media_type)) => media_type,
+            (None, None) => return Err(anyhow!("unable to detect manifest type")),
+        };
+
+        Ok(match media_type.as_str() {
+            Self::DOCKER_V1 | Self::DOCKER_V1_SIGNED => {
+                Self::DockerV1(serde_json::from_slice(body)?)
+            }
+            Self::DOCKER_V2 => Self::DockerV2(serde_json::from_slice(body)?),
+            Self::DOCKER_V2_LIST => Self::DockerV2List(serde_json::from_slice(body)?),
+            Self::OCI => Self::Oci(serde_json::from_slice(body)?),
+            Self::OCI_INDEX => Self::OciIndex(serde_json::from_slice(body)?),
+            other => return Err(anyhow!("unsupported manifest type: {}", other)),
+        })
+    }
+
+    /// The media type of the manifest
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Self::DockerV1(..) => Self::DOCKER_V1,
+            Self::DockerV2(..) => Self::DOCKER_V2,
+            Self::DockerV2List(..) => Self::DOCKER_V2_LIST,
+            Self::Oci(..) => Self::OCI,
+            Self::OciIndex(..) => Self::OCI_INDEX,
+        }
+    }
+
+    /// Detects the media type from the manifest body
+    fn detect(body: &[u8]) -> Result<Option<String>> {
+        #[derive(Deserialize)]
+        struct Probe {
+            #[serde(rename = "schemaVersion")]
+            schema_version: usize,
+
+            #[serde(rename = "mediaType")]
+            media_type: Option<String>,
+
+            manifests: Option<IgnoredAny>,
+
+            config: Option<IgnoredAny>,
+        }
+
+        let probe: Probe = serde_json::from_slice(body)?;
+        Ok(match probe {
+            Probe {
+                media_type: Some(media_type),
+                ..
+            } => Some(media_type.to_ascii_lowercase()),
+
+            Probe {
+                schema_version: 1, ..
+            } => Some(Self::DOCKER_V1.into()),
+
+            // The OCI formats don't require the `mediaType` field.
+            Probe {
+                schema_version: 2,
+                manifests: Some(..),
+                ..
+            } => Some(Self::OCI_INDEX.into()),
+
+            Probe {
+                schema_version: 2,
+                config: Some(..),
+                ..
+            } => Some(Self::OCI.into()),
+
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Manifest;
+
+    const V2: &str = r#"{
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+        "config": {
+            "mediaType": "application/vnd.docker.container.image.v1+json",
+            "size": 1469,
+            "digest": "sha256:beae173ccac6ad749f76713cf4440fe3d21d1043fe616dfbe30775815d1d0f6a"
+        },
+        "layers": [{
+            "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+            "size": 772788,
+            "digest": "sha256:5cc84ad355aaa64f46ea9c7bbcc319a9d808ab15088a27209c9e70ef86e5a2aa"
+        }]
+    }"#;
+
+    const INDEX: &str = r#"{
+        "schemaVersion": 2,
+        "manifests": [{
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "size": 7143,
+            "digest": "sha256:e692418e4cbaf90ca69d05a66403747baa33ee08806650b51fab815ad7fc331f",
+            "platform": { "architecture": "ppc64le", "os": "linux" }
+        }]
+    }"#;
+
+    #[test]
+    fn content_type() {
+        let ct = "application/vnd.docker.distribution.manifest.v2+json; charset=utf-8";
+        let manifest = Manifest::parse(Some(ct), V2.as_bytes()).unwrap();
+        assert!(matches!(manifest, Manifest::DockerV2(..)));
+
+        let v1 = r#"{
+            "schemaVersion": 1,
+            "name": "library/debian",
+            "tag": "latest",
+            "architecture": "amd64",
+            "fsLayers": []
+        }"#;
+        let manifest = Manifest::parse(Some(Manifest::DOCKER_V1_SIGNED), v1.as_bytes()).unwrap();
+        assert!(matches!(manifest, Manifest::DockerV1(..)));
+
+        // The OCI manifest has the same shape, but we must not guess.
+        let err = Manifest::parse(Some(Manifest::OCI), V2.as_bytes()).unwrap_err();
+        assert!(err.to_string().starts_with("manifest type mismatch"));
+    }
+
+    #[test]
+    fn detect() {
+        let manifest = Manifest::parse(Some("application/json"), V2.as_bytes()).unwrap();
+        assert!(matches!(manifest, Manifest::DockerV2(..)));
+
+        let manifest = Manifest::parse(Some("text/plain; charset=utf-8"), V2.as_bytes()).unwrap();
+        assert!(matches!(manifest, Manifest::DockerV2(..)));
+
+        let manifest = Manifest::parse(None, INDEX.as_bytes()).unwrap();
+        assert!(matches!(manifest, Manifest::OciIndex(..)));
+    }
+
+    #[test]
+    fn unsupported() {
+        let body = r#"{ "schemaVersion": 2, "mediaType": "application/x-foo" }"#;
+        let err = Manifest::parse(None, body.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unsupported manifest type: application/x-foo"
+        );
+
+        // An unknown `Content-Type` is not overridden by the body.
+        let err = Manifest::parse(Some("application/octet-stream"), V2.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unsupported manifest type: application/octet-stream"
+        );
+    }
 }
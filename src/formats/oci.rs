@@ -3,11 +3,15 @@
 
 use std::collections::HashMap;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+use super::docker::v2::Platform;
 use super::Digest;
 
-#[derive(Clone, Debug, Deserialize)]
+/// The annotation with the name of a manifest in an index
+pub const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Descriptor {
     #[serde(rename = "mediaType")]
     pub media_type: String,
@@ -16,25 +20,49 @@ pub struct Descriptor {
 
     pub size: u64,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub urls: Vec<String>,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub annotations: HashMap<String, String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+}
+
+impl Descriptor {
+    /// The name of the manifest (see `REF_NAME`), if any
+    pub fn name(&self) -> Option<&str> {
+        self.annotations.get(REF_NAME).map(String::as_str)
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Manifest {
     #[serde(rename = "schemaVersion")]
     pub schema_version: usize,
 
-    #[serde(rename = "mediaType")]
+    #[serde(rename = "mediaType", skip_serializing_if = "Option::is_none")]
     pub media_type: Option<String>,
 
     pub config: Descriptor,
 
     pub layers: Vec<Descriptor>,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Index {
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: usize,
+
+    #[serde(rename = "mediaType", skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+
+    pub manifests: Vec<Descriptor>,
+
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub annotations: HashMap<String, String>,
 }
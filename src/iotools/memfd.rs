@@ -0,0 +1,18 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Error, Result};
+use std::os::unix::io::FromRawFd;
+
+/// Creates an anonymous file that lives in memory (see `memfd_create(2)`)
+pub fn memfd(name: &str) -> Result<File> {
+    let name = CString::new(name)?;
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
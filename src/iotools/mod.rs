@@ -4,12 +4,16 @@
 //! Utility types for dealing with readers and writers
 
 mod either;
+mod memfd;
+#[allow(dead_code)]
 mod muxer;
 mod siphon;
 pub mod threaded;
 mod validator;
 
 pub use either::Either;
+pub use memfd::memfd;
+#[allow(unused_imports)]
 pub use muxer::Muxer;
 pub use siphon::Siphon;
 pub use validator::{Validatable, Validator};
@@ -17,6 +17,10 @@ impl<R: Read, W: Write> Siphon<R, W> {
         Self(reader, writer)
     }
 
+    pub fn reader(&self) -> &R {
+        &self.0
+    }
+
     pub fn writer(&self) -> &W {
         &self.1
     }
@@ -25,6 +25,10 @@ impl<R: Read, W: Validatable> Validator<R, W> {
         Self(Siphon::new(reader, writer))
     }
 
+    pub fn reader(&self) -> &R {
+        self.0.reader()
+    }
+
     pub fn writer(&self) -> &W {
         self.0.writer()
     }
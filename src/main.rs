@@ -5,10 +5,14 @@
 #![allow(clippy::useless_conversion)]
 
 mod api;
+mod cmdline;
 mod commands;
 mod formats;
 mod iotools;
 
+#[cfg(test)]
+mod fixtures;
+
 use clap::Parser;
 use commands::Command;
 